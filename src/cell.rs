@@ -1,13 +1,23 @@
-use crate::constants;
-use crate::document::Document;
-use crate::parser;
+use crate::parser::{self, Node, ParseError};
+use crate::value::{CellError, Value};
+
+use std::cell::OnceCell;
 
 #[derive(Default)] // TODO: implement Copy?
 pub struct Cell {
     text: String,
+    // byte offset into `text`
     cursor_position: usize,
+    // the first character shown
     view_start: usize,
-    evaluated: String,
+    // what's displayed in place of `text` while not editing
+    evaluated: Option<String>,
+    // result of the last evaluation of the formula in `text`
+    value: Value,
+    // the formula in `text`, parsed the first time it's needed and dropped whenever `text` changes
+    ast: OnceCell<Result<Node, ParseError>>,
+    // `text` read as a literal, cached the same way as `ast` for cells without a formula
+    literal: OnceCell<Value>,
 }
 
 impl From<char> for Cell {
@@ -16,7 +26,10 @@ impl From<char> for Cell {
             text: String::from(c),
             cursor_position: 1,
             view_start: 0,
-            evaluated: String::default(),
+            evaluated: None,
+            value: Value::Empty,
+            ast: OnceCell::new(),
+            literal: OnceCell::new(),
         }
     }
 }
@@ -27,53 +40,78 @@ impl From<String> for Cell {
             cursor_position: text.len(),
             text,
             view_start: 0,
-            evaluated: String::default(),
+            evaluated: None,
+            value: Value::Empty,
+            ast: OnceCell::new(),
+            literal: OnceCell::new(),
         }
     }
 }
 
 impl Cell {
-    pub fn to_str(&self) -> &str {
-        if self.evaluated.len() > 0 {
-            &self.evaluated
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// The typed value of the cell: the result of its formula, or its text read as a literal.
+    /// Literals are only read again after the text has been edited.
+    pub fn value(&self) -> &Value {
+        if self.text.starts_with('=') {
+            &self.value
         } else {
-            &self.text
+            self.literal.get_or_init(|| Value::from_literal(&self.text))
         }
     }
 
+    /// The parsed formula, or `None` if the cell doesn't hold one. The text is only parsed again
+    /// after it's been edited.
+    pub fn formula(&self) -> Option<&Result<Node, ParseError>> {
+        let formula = self.text.strip_prefix('=')?;
+
+        Some(self.ast.get_or_init(|| parser::parse_formula(formula)))
+    }
+
+    pub fn to_str(&self) -> &str {
+        self.evaluated.as_deref().unwrap_or(&self.text)
+    }
+
     pub fn to_string(&self) -> &String {
-        if self.evaluated.len() > 0 {
-            &self.evaluated
-        } else {
-            &self.text
-        }
+        self.evaluated.as_ref().unwrap_or(&self.text)
     }
 
-    pub fn view(&self) -> String {
-        let text = if self.evaluated.len() > 0 {
-            &self.evaluated
-        } else {
-            &self.text
-        };
+    pub fn cursor_position(&self) -> usize {
+        self.cursor_position
+    }
 
-        let mut end = self.view_start.saturating_add(constants::CELL_VIEW_LEN);
-        if end > text.len() {
-            end = text.len();
-        }
+    /// The part of the cell that fits in `width` characters. With `raw`, formulas are shown
+    /// instead of their results.
+    pub fn view(&self, raw: bool, width: usize) -> String {
+        let text = if raw { self.text() } else { self.to_str() };
 
-        text.get(self.view_start..end).unwrap().to_string()
+        text.chars().skip(self.view_start).take(width).collect()
     }
 
-    pub fn len(&self) -> usize {
-        if self.evaluated.len() > 0 {
-            self.evaluated.len()
-        } else {
-            self.text.len()
+    /// Scrolls the view as little as possible to keep the cursor within `width` characters.
+    pub fn scroll_to_cursor(&mut self, width: usize) {
+        let cursor = self
+            .to_str()
+            .get(..self.cursor_position)
+            .map_or(0, |before| before.chars().count());
+
+        if cursor < self.view_start {
+            self.view_start = cursor;
+        } else if cursor > self.view_start.saturating_add(width) {
+            self.view_start = cursor - width;
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.to_str().len()
+    }
+
     pub fn insert(&mut self, c: char) {
         self.text.insert(self.cursor_position, c);
+        self.text_changed();
 
         self.move_cursor(crossterm::event::KeyCode::Right);
     }
@@ -81,19 +119,15 @@ impl Cell {
     pub fn move_cursor(&mut self, key: crossterm::event::KeyCode) {
         match key {
             crossterm::event::KeyCode::Left | crossterm::event::KeyCode::Char('h') => {
-                self.cursor_position = self.cursor_position.saturating_sub(1);
-                if self.cursor_position < self.view_start {
-                    self.view_start = self.view_start.saturating_sub(1);
-                }
+                self.cursor_position -= self.char_before_cursor().map_or(0, char::len_utf8);
             }
-            crossterm::event::KeyCode::Right | crossterm::event::KeyCode::Char('l') => {
-                if self.cursor_position < self.len() {
-                    self.cursor_position = self.cursor_position.saturating_add(1);
-
-                    if self.cursor_position > self.view_start + constants::CELL_VIEW_LEN {
-                        self.view_start = self.view_start.saturating_add(1);
-                    }
-                }
+            crossterm::event::KeyCode::Right | crossterm::event::KeyCode::Char('l')
+                if self.cursor_position < self.len() =>
+            {
+                self.cursor_position += self.to_str()[self.cursor_position..]
+                    .chars()
+                    .next()
+                    .map_or(0, char::len_utf8);
             }
             _ => {}
         }
@@ -101,37 +135,131 @@ impl Cell {
 
     pub fn handle_delete(&mut self, key: crossterm::event::KeyCode) {
         match key {
-            crossterm::event::KeyCode::Delete => {
-                if self.cursor_position < self.len() {
-                    self.text.remove(self.cursor_position);
-                }
+            crossterm::event::KeyCode::Delete if self.cursor_position < self.len() => {
+                self.text.remove(self.cursor_position);
+                self.text_changed();
             }
-            crossterm::event::KeyCode::Backspace => {
-                if self.cursor_position > 0 {
-                    self.text.remove(self.cursor_position - 1);
-                    self.cursor_position = self.cursor_position.saturating_sub(1);
-                }
+            crossterm::event::KeyCode::Backspace if self.cursor_position > 0 => {
+                self.move_cursor(crossterm::event::KeyCode::Left);
+                self.text.remove(self.cursor_position);
+                self.text_changed();
             }
             _ => {}
         }
     }
 
-    pub fn set_evaluated(&mut self, evaluated: String) {
-        self.evaluated = evaluated;
+    fn char_before_cursor(&self) -> Option<char> {
+        self.to_str()[..self.cursor_position].chars().next_back()
+    }
+
+    /// Replaces the whole text, e.g. when a structural edit moves the references in a formula.
+    pub fn set_text(&mut self, text: String) {
+        self.cursor_position = text.len();
+        self.view_start = 0;
+        self.text = text;
+        self.text_changed();
+    }
+
+    // Drops everything derived from the text
+    fn text_changed(&mut self) {
+        self.ast.take();
+        self.literal.take();
+    }
+
+    pub fn set_evaluated(&mut self, value: Value) {
+        self.evaluated = Some(value.to_string());
+        self.value = value;
         self.cursor_position = 0;
         self.view_start = 0;
     }
 
+    /// Shows the text in place of the result for editing. The view is scrolled back to the start,
+    /// ready for `scroll_to_cursor`.
     pub fn clear_evaluated(&mut self, place_at_end: bool) {
-        let was_evaluated = self.evaluated.len() > 0;
-        self.evaluated = "".to_string();
+        let was_evaluated = self.evaluated.take().is_some();
+        self.view_start = 0;
 
         if place_at_end {
             self.cursor_position = self.text.len();
-            self.view_start = self.text.len().saturating_sub(constants::CELL_VIEW_LEN);
         } else if was_evaluated {
-            self.cursor_position = 1;
-            self.view_start = 0;
+            // jump straight to where the formula failed to parse, just past its `=`
+            self.cursor_position = match &self.value {
+                Value::Error(CellError {
+                    offset: Some(offset),
+                    ..
+                }) => offset.saturating_add(1).min(self.text.len()),
+                _ => 1,
+            };
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::Document;
+    use crossterm::event::KeyCode;
+
+    #[test]
+    fn views_are_measured_in_characters() {
+        let mut cell = Cell::from(String::from("aéééééé"));
+
+        assert_eq!(cell.view(false, 4), "aééé");
+
+        cell.scroll_to_cursor(4);
+        assert_eq!(cell.view(false, 4), "éééé");
+    }
+
+    #[test]
+    fn the_cursor_moves_over_whole_characters() {
+        let mut cell = Cell::from('a');
+        cell.insert('é');
+        cell.insert('b');
+        cell.move_cursor(KeyCode::Left);
+        cell.move_cursor(KeyCode::Left);
+        cell.insert('ü');
+        assert_eq!(cell.text(), "aüéb");
+
+        cell.handle_delete(KeyCode::Delete);
+        cell.handle_delete(KeyCode::Backspace);
+        assert_eq!(cell.text(), "ab");
+    }
+
+    fn result(cell: &Cell) -> String {
+        parser::evaluate_formula(cell.formula().unwrap(), &Document::default()).to_string()
+    }
+
+    #[test]
+    fn edits_drop_the_cached_formula() {
+        let mut cell = Cell::from(String::from("=1+2"));
+        assert_eq!(result(&cell), "3");
+
+        cell.insert('0');
+        assert_eq!(result(&cell), "21");
+
+        cell.handle_delete(KeyCode::Backspace);
+        cell.handle_delete(KeyCode::Backspace);
+        assert_eq!(result(&cell), "#PARSE!");
+
+        cell.handle_delete(KeyCode::Backspace);
+        assert_eq!(result(&cell), "1");
+
+        cell.set_text(String::from("=2*3"));
+        assert_eq!(result(&cell), "6");
+    }
+
+    #[test]
+    fn edits_drop_the_cached_literal() {
+        let mut cell = Cell::from(String::from("12"));
+        assert_eq!(cell.value().to_string(), "12");
+
+        cell.insert('3');
+        assert_eq!(cell.value().to_string(), "123");
+
+        cell.handle_delete(KeyCode::Backspace);
+        assert_eq!(cell.value().to_string(), "12");
+
+        cell.set_text(String::from("abc"));
+        assert_eq!(cell.value().to_string(), "abc");
+    }
+}
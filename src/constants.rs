@@ -1,9 +1,9 @@
 use std::time::Duration;
 
-// Viewbox Sizes
-pub const CELL_VIEW_LEN: usize = 12;
-pub const SHEET_VIEWBOX_HEIGHT: u16 = 12;
-pub const SHEET_VIEWBOX_WIDTH: u16 = 10;
+// Cell Sizes - width in characters of text, height in lines (borders included)
+pub const CELL_WIDTH: u16 = 12;
+pub const CELL_HEIGHT: u16 = 3;
+pub const MIN_CELL_HEIGHT: u16 = 3;
 
 // Times
 pub const POLL_TIME: u64 = 250;
@@ -0,0 +1,348 @@
+use std::cmp::Ordering;
+
+use crate::document::Document;
+use crate::editor::Position;
+use crate::functions;
+use crate::parser::{self, Node};
+use crate::value::{CellError, ErrorKind, Value};
+use crate::Cell;
+
+#[derive(Clone, Copy)]
+enum Operator {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+// A condition like `">10"`, `"iron*"` or `"<>"`, tested against each cell of a range
+struct Criterion {
+    operator: Operator,
+    operand: Value,
+}
+
+impl Criterion {
+    // Text criteria may start with a comparison operator, and the rest is read the same way as a
+    // cell's text. Anything else has to be equal.
+    fn from(value: Value) -> Self {
+        let Value::String(text) = value else {
+            return Self {
+                operator: Operator::Equal,
+                operand: value,
+            };
+        };
+
+        let operators = [
+            ("<=", Operator::LessEqual),
+            (">=", Operator::GreaterEqual),
+            ("<>", Operator::NotEqual),
+            ("<", Operator::Less),
+            (">", Operator::Greater),
+            ("=", Operator::Equal),
+        ];
+
+        let (operator, operand) = operators
+            .into_iter()
+            .find_map(|(prefix, operator)| {
+                text.strip_prefix(prefix).map(|operand| (operator, operand))
+            })
+            .unwrap_or((Operator::Equal, &text));
+
+        Self {
+            operator,
+            operand: Value::from_literal(operand),
+        }
+    }
+
+    fn matches(&self, value: &Value) -> bool {
+        let ordering = match (value, &self.operand) {
+            (Value::Number(_), Value::Number(_))
+            | (Value::String(_), Value::String(_))
+            | (Value::Boolean(_), Value::Boolean(_)) => value.compare(&self.operand).ok(),
+            _ => None,
+        };
+
+        match self.operator {
+            Operator::Equal => self.equals(value),
+            Operator::NotEqual => !self.equals(value),
+            Operator::Less => ordering.is_some_and(Ordering::is_lt),
+            Operator::LessEqual => ordering.is_some_and(Ordering::is_le),
+            Operator::Greater => ordering.is_some_and(Ordering::is_gt),
+            Operator::GreaterEqual => ordering.is_some_and(Ordering::is_ge),
+        }
+    }
+
+    fn equals(&self, value: &Value) -> bool {
+        match (value, &self.operand) {
+            (Value::Empty, Value::Empty) => true,
+            (Value::String(text), Value::String(pattern)) => wildcard_match(
+                &pattern.to_lowercase().chars().collect::<Vec<char>>(),
+                &text.to_lowercase().chars().collect::<Vec<char>>(),
+            ),
+            (Value::Number(_), Value::Number(_)) | (Value::Boolean(_), Value::Boolean(_)) => {
+                value.compare(&self.operand).is_ok_and(Ordering::is_eq)
+            }
+            _ => false,
+        }
+    }
+}
+
+// A criterion along with the range it's tested against
+struct Condition {
+    start: Position,
+    end: Position,
+    criterion: Criterion,
+}
+
+pub fn call(name: &str, args: &[Node], document: &Document) -> Result<Value, CellError> {
+    // the single condition functions take the range to aggregate last, the -IFS ones first
+    let (values, conditions) = match name {
+        "COUNTIF" => {
+            functions::check_arity(name, args, 2, 2)?;
+
+            (None, args)
+        }
+        "SUMIF" | "AVERAGEIF" => {
+            functions::check_arity(name, args, 2, 3)?;
+
+            (Some(args.get(2).unwrap_or(&args[0])), &args[..2])
+        }
+        "COUNTIFS" => (None, args),
+        _ => match args.split_first() {
+            Some((values, conditions)) => (Some(values), conditions),
+            None => (None, args),
+        },
+    };
+
+    if conditions.is_empty() || conditions.len() % 2 != 0 {
+        return Err(CellError::value(format!(
+            "{name} takes pairs of a range and a criterion"
+        )));
+    }
+
+    let conditions = conditions
+        .chunks(2)
+        .map(|pair| {
+            let (start, end) = range(&pair[0], document)?;
+            let criterion = Criterion::from(parser::evaluate(&pair[1], document)?);
+
+            Ok(Condition {
+                start,
+                end,
+                criterion,
+            })
+        })
+        .collect::<Result<Vec<Condition>, CellError>>()?;
+
+    let matches = matching_offsets(&conditions, document)?;
+
+    let Some(values) = values else {
+        return Ok(functions::count(matches.len()));
+    };
+
+    // the range to aggregate is read at the same offsets. The single condition functions only
+    // use its corner, like other spreadsheets.
+    let (start, end) = range(values, document)?;
+
+    if name.ends_with("IFS") && !same_size((start, end), (conditions[0].start, conditions[0].end)) {
+        return Err(CellError::value(
+            "The range to aggregate must be the same size as the criteria ranges",
+        ));
+    }
+    let mut numbers = vec![];
+
+    for (row, col) in matches {
+        match document
+            .get_cell(start.col + col, start.row + row)
+            .map(Cell::value)
+        {
+            Some(Value::Number(num)) => numbers.push(*num),
+            Some(Value::Error(err)) => return Err(err.clone()),
+            _ => {}
+        }
+    }
+
+    if name.starts_with("SUM") {
+        return parser::number_value(numbers.iter().sum());
+    }
+
+    if numbers.is_empty() {
+        return Err(CellError::new(
+            ErrorKind::DivZero,
+            format!("No cells matched the criteria of {name}"),
+        ));
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    parser::number_value(numbers.iter().sum::<f64>() / numbers.len() as f64)
+}
+
+// The (row, col) offsets into the ranges where every condition holds, in row-major order. When a
+// criterion can't match an empty cell, only the cells that exist in its range have to be checked.
+fn matching_offsets(
+    conditions: &[Condition],
+    document: &Document,
+) -> Result<Vec<(usize, usize)>, CellError> {
+    let first = &conditions[0];
+    let (height, width) = (
+        first.end.row - first.start.row,
+        first.end.col - first.start.col,
+    );
+
+    if !conditions
+        .iter()
+        .all(|condition| same_size((condition.start, condition.end), (first.start, first.end)))
+    {
+        return Err(CellError::value(
+            "All criteria ranges must be the same size",
+        ));
+    }
+
+    let candidates: Vec<(usize, usize)> = match conditions
+        .iter()
+        .find(|condition| !condition.criterion.matches(&Value::Empty))
+    {
+        Some(condition) => document
+            .cells_in_range(&condition.start, &condition.end)
+            .into_iter()
+            .map(|(pos, _)| (pos.row - condition.start.row, pos.col - condition.start.col))
+            .collect(),
+        None => (0..=height)
+            .flat_map(|row| (0..=width).map(move |col| (row, col)))
+            .collect(),
+    };
+
+    Ok(candidates
+        .into_iter()
+        .filter(|(row, col)| {
+            conditions.iter().all(|condition| {
+                let value = document
+                    .get_cell(condition.start.col + col, condition.start.row + row)
+                    .map_or(Value::Empty, |cell| cell.value().clone());
+
+                condition.criterion.matches(&value)
+            })
+        })
+        .collect())
+}
+
+fn same_size(a: (Position, Position), b: (Position, Position)) -> bool {
+    a.1.row - a.0.row == b.1.row - b.0.row && a.1.col - a.0.col == b.1.col - b.0.col
+}
+
+fn range(arg: &Node, document: &Document) -> Result<(Position, Position), CellError> {
+    parser::reference_bounds(arg, document)?
+        .ok_or_else(|| CellError::value("Expected a range to test against the criteria"))
+}
+
+// One piece of a wildcard pattern
+#[derive(Clone, Copy, PartialEq)]
+enum Token {
+    // `*`, any run of characters
+    Any,
+    // `?`, any single character
+    One,
+    Char(char),
+}
+
+// Matches `text` against a pattern where `*` is any run of characters, `?` is any single
+// character and `~` escapes the character after it. Only the last `*` is ever backtracked to, which
+// keeps this linear in each `*` rather than exponential in the number of them.
+fn wildcard_match(pattern: &[char], text: &[char]) -> bool {
+    let mut tokens = vec![];
+    let mut chars = pattern.iter();
+
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '*' => Token::Any,
+            '?' => Token::One,
+            '~' => Token::Char(*chars.next().unwrap_or(&'~')),
+            c => Token::Char(*c),
+        });
+    }
+
+    let (mut token, mut char) = (0, 0);
+    // the token after the last `*` seen, and the text position it's being tried at
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while char < text.len() {
+        match tokens.get(token) {
+            Some(Token::Any) => {
+                token += 1;
+                backtrack = Some((token, char));
+            }
+            Some(Token::One) => (token, char) = (token + 1, char + 1),
+            Some(Token::Char(c)) if *c == text[char] => (token, char) = (token + 1, char + 1),
+            // let the last `*` swallow one more character and try again from there
+            _ => match backtrack {
+                Some((after_star, star_char)) => {
+                    backtrack = Some((after_star, star_char + 1));
+                    (token, char) = (after_star, star_char + 1);
+                }
+                None => return false,
+            },
+        }
+    }
+
+    tokens[token..].iter().all(|token| *token == Token::Any)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        wildcard_match(
+            &pattern.chars().collect::<Vec<char>>(),
+            &text.chars().collect::<Vec<char>>(),
+        )
+    }
+
+    fn eval(formula: &str) -> String {
+        Document::from_rows(&[
+            &["iron ore", "5"],
+            &["Iron bar", "7"],
+            &["copper", "2"],
+            &["", "11"],
+            &["*", "1"],
+        ])
+        .eval(formula)
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("iron*", "iron ore"));
+        assert!(matches("*", ""));
+        assert!(matches("c?pper", "copper"));
+        assert!(!matches("c?pper", "cpper"));
+        assert!(matches("*o*e*", "iron ore"));
+        assert!(!matches("*x*", "iron ore"));
+        assert!(matches("~*", "*"));
+        assert!(!matches("~*", "a"));
+        assert!(matches("50~?", "50?"));
+        assert!(matches("a~", "a~"));
+    }
+
+    #[test]
+    fn many_stars_dont_backtrack_exponentially() {
+        let text = "a".repeat(69);
+
+        assert!(!matches("*a*a*a*a*a*a*a*b", &text));
+        assert!(matches("*a*a*a*a*a*a*a*", &text));
+    }
+
+    #[test]
+    fn criteria() {
+        assert_eq!(eval("COUNTIF(A1:A5, \"iron*\")"), "2");
+        assert_eq!(eval("SUMIF(A1:A5, \"IRON*\", B1:B5)"), "12");
+        assert_eq!(eval("SUMIF(B1:B5, \">5\")"), "18");
+        assert_eq!(eval("COUNTIF(B1:B5, \"<>7\")"), "4");
+        assert_eq!(eval("COUNTIF(A1:A5, \"\")"), "1");
+        assert_eq!(eval("COUNTIF(A1:A5, \"~*\")"), "1");
+        assert_eq!(eval("AVERAGEIF(A1:A5, \"gold\", B1:B5)"), "#DIV/0!");
+        assert_eq!(eval("SUMIFS(B1:B5, A1:A5, \"*r*\", B1:B5, \"<7\")"), "7");
+        assert_eq!(eval("COUNTIFS(A1:A5, \"?*\", B1:B4, \">1\")"), "#VALUE!");
+    }
+}
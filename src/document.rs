@@ -1,17 +1,63 @@
+use crate::constants;
 use crate::editor::Position;
-use crate::parser;
+use crate::parser::{self, Node, StructuralEdit};
+use crate::value::{CellError, ErrorKind, Value};
 use crate::Cell;
 use crate::Row;
 
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write as _;
 use std::fs::{self, File};
 use std::io::Write;
 
+const DIRECTIVE_PREFIX: &str = "#!";
+
 #[derive(Default)]
 pub struct Document {
     rows: HashMap<usize, Row>,
     max_row: usize,
     pub filename: Option<String>,
+    // formula cell -> the cells and ranges it references
+    precedents: HashMap<Position, Vec<Area>>,
+    // cell -> formula cells that reference it on its own
+    dependents: HashMap<Position, HashSet<Position>>,
+    // column -> first row -> the last row and the formula cell of every referenced range covering
+    // that column. A cell's range dependents are found among the ranges starting at or above it,
+    // so a huge range costs an entry per column rather than an edge per cell.
+    range_dependents: HashMap<usize, BTreeMap<usize, Vec<(usize, Position)>>>,
+    // uppercased name -> what it stands for, e.g. `IRON_RATE -> B2`
+    names: BTreeMap<String, Name>,
+    // column -> width in characters, for the columns that have been resized
+    column_widths: BTreeMap<usize, u16>,
+    // heights of the rows that have been resized, in terminal lines
+    row_heights: BTreeMap<usize, u16>,
+}
+
+// A block of cells referenced by a formula, from `start` to `end` inclusive. A single cell is
+// an area with both corners the same.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Area {
+    start: Position,
+    end: Position,
+}
+
+impl Area {
+    fn contains(&self, pos: &Position) -> bool {
+        (self.start.row..=self.end.row).contains(&pos.row)
+            && (self.start.col..=self.end.col).contains(&pos.col)
+    }
+
+    fn is_cell(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+// A name's definition as typed, along with its parsed form so formulas using it don't have to
+// parse it again
+struct Name {
+    definition: String,
+    node: Node,
 }
 
 impl Document {
@@ -21,9 +67,8 @@ impl Document {
         if let Err(err) = contents_r {
             if err.kind() == std::io::ErrorKind::NotFound {
                 return Ok(Self {
-                    rows: HashMap::new(),
-                    max_row: 0,
                     filename: Some(filename),
+                    ..Self::default()
                 });
             }
 
@@ -34,16 +79,21 @@ impl Document {
         let mut rows: HashMap<usize, Row> = HashMap::new();
         let mut max_row: usize = 0;
 
-        for (row_idx, line) in contents.split('\n').enumerate() {
+        // lines starting with `#!` hold settings that aren't cells, like names
+        let (directives, lines): (Vec<&str>, Vec<&str>) = contents
+            .split('\n')
+            .partition(|line| line.starts_with(DIRECTIVE_PREFIX));
+
+        for (row_idx, line) in lines.into_iter().enumerate() {
             if line.is_empty() {
                 continue;
             }
 
             let mut current_row = Row::default();
 
-            for (col_idx, text) in line.split(',').enumerate() {
+            for (col_idx, text) in split_csv_line(line).into_iter().enumerate() {
                 if !text.is_empty() {
-                    current_row.init_cell_at(col_idx, text.to_string());
+                    current_row.init_cell_at(col_idx, text);
                 }
             }
 
@@ -51,15 +101,24 @@ impl Document {
             max_row = row_idx;
         }
 
-        Ok(Self {
+        let mut document = Self {
             rows,
             max_row,
             filename: Some(filename),
-        })
+            ..Self::default()
+        };
+
+        for directive in directives {
+            document.load_directive(&directive[DIRECTIVE_PREFIX.len()..]);
+        }
+
+        document.recalculate_all();
+
+        Ok(document)
     }
 
     pub fn get_row(&self, row_idx: usize) -> Option<&Row> {
-        return self.rows.get(&row_idx);
+        self.rows.get(&row_idx)
     }
 
     pub fn insert_at(&mut self, position: &Position, c: char) {
@@ -85,6 +144,43 @@ impl Document {
         None
     }
 
+    /// Returns the non-empty cells inside the rectangle from `start` to `end` (inclusive), in
+    /// row-major order. Walks whichever of the range or the sparse storage is smaller, so huge
+    /// ranges over small sheets stay cheap.
+    pub fn cells_in_range(&self, start: &Position, end: &Position) -> Vec<(Position, &Cell)> {
+        let mut rows: Vec<(usize, &Row)> = if end.row - start.row < self.rows.len() {
+            (start.row..=end.row)
+                .filter_map(|row_idx| self.rows.get(&row_idx).map(|row| (row_idx, row)))
+                .collect()
+        } else {
+            let mut rows: Vec<(usize, &Row)> = self
+                .rows
+                .iter()
+                .filter(|(row_idx, _)| (start.row..=end.row).contains(*row_idx))
+                .map(|(row_idx, row)| (*row_idx, row))
+                .collect();
+            rows.sort_unstable_by_key(|(row_idx, _)| *row_idx);
+
+            rows
+        };
+
+        let mut cells = vec![];
+        let mut row_cells = vec![];
+
+        for (row_idx, row) in rows.drain(..) {
+            row.cells_in(start.col, end.col, &mut row_cells);
+
+            cells.extend(
+                row_cells
+                    .drain(..)
+                    .filter(|(_, cell)| !cell.text().is_empty())
+                    .map(|(col, cell)| (Position { col, row: row_idx }, cell)),
+            );
+        }
+
+        cells
+    }
+
     pub fn get_mut_cell(&mut self, position: &Position) -> Option<&mut Cell> {
         if let Some(row) = self.rows.get_mut(&position.row) {
             return row.get_mut(position.col);
@@ -99,10 +195,7 @@ impl Document {
 
     pub fn save(&mut self) -> std::io::Result<()> {
         if self.filename.is_none() {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "No filename",
-            ));
+            return Err(std::io::Error::other("No filename"));
         }
 
         let mut doc_string = String::new();
@@ -111,7 +204,7 @@ impl Document {
             if let Some(row) = self.get_row(row_idx) {
                 for col_idx in 0..row.max_col().saturating_add(1) {
                     if let Some(cell) = self.get_cell(col_idx, row_idx) {
-                        doc_string.push_str(cell.to_string());
+                        doc_string.push_str(&escape_csv(cell.text()));
                     }
                     doc_string.push(',');
                 }
@@ -120,6 +213,26 @@ impl Document {
             doc_string.push('\n');
         }
 
+        for (name, definition) in self.names() {
+            let _ = writeln!(doc_string, "{DIRECTIVE_PREFIX}name {name} {definition}");
+        }
+
+        for (col, width) in &self.column_widths {
+            let _ = writeln!(
+                doc_string,
+                "{DIRECTIVE_PREFIX}colwidth {} {width}",
+                parser::column_name(*col)
+            );
+        }
+
+        for (row, height) in &self.row_heights {
+            let _ = writeln!(
+                doc_string,
+                "{DIRECTIVE_PREFIX}rowheight {} {height}",
+                row + 1
+            );
+        }
+
         let mut file = File::create(self.filename.as_ref().unwrap())?;
 
         file.write_all(doc_string.as_bytes())?;
@@ -127,64 +240,908 @@ impl Document {
         Ok(())
     }
 
+    // Settings saved alongside the cells. Anything unrecognised is skipped, so files saved by
+    // newer versions still open.
+    fn load_directive(&mut self, directive: &str) {
+        let mut parts = directive.splitn(3, ' ');
+
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("name"), Some(name), Some(definition)) => {
+                let _ = self.insert_name(name, definition);
+            }
+            (Some("colwidth"), Some(column), Some(width)) => {
+                if let (Some(col), Ok(width @ 1..)) =
+                    (parser::column_index(column), width.trim().parse::<u16>())
+                {
+                    self.column_widths.insert(col, width);
+                }
+            }
+            (Some("rowheight"), Some(row), Some(height)) => {
+                // a row any shorter than the minimum has no room for its text between the borders
+                if let (Ok(row @ 1..), Ok(height)) =
+                    (row.parse::<usize>(), height.trim().parse::<u16>())
+                {
+                    self.row_heights
+                        .insert(row - 1, height.max(constants::MIN_CELL_HEIGHT));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Defines `name` (case insensitive) as a shorthand for `definition`, e.g. `B2` or `A1:A20`,
+    /// replacing any previous definition.
+    pub fn define_name(&mut self, name: &str, definition: &str) -> Result<(), String> {
+        self.insert_name(name, definition)?;
+        self.recalculate_all();
+
+        Ok(())
+    }
+
+    fn insert_name(&mut self, name: &str, definition: &str) -> Result<(), String> {
+        let definition = definition.trim();
+        let definition = definition.strip_prefix('=').unwrap_or(definition);
+
+        parser::validate_name(name)?;
+        let node = parser::parse_name_definition(definition)?;
+
+        self.names.insert(
+            name.to_ascii_uppercase(),
+            Name {
+                definition: definition.to_string(),
+                node,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// The definition of a name, looked up case insensitively.
+    pub fn name(&self, name: &str) -> Option<&str> {
+        self.names
+            .get(&name.to_ascii_uppercase())
+            .map(|name| name.definition.as_str())
+    }
+
+    /// The parsed definition of a name that's already uppercased, as it is in formulas.
+    pub fn name_node(&self, name: &str) -> Option<&Node> {
+        self.names.get(name).map(|name| &name.node)
+    }
+
+    /// All defined names and their definitions, sorted by name.
+    pub fn names(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.names
+            .iter()
+            .map(|(name, definition)| (name, &definition.definition))
+    }
+
+    /// The width of a column in characters, if it's been resized.
+    pub fn column_width(&self, col: usize) -> Option<u16> {
+        self.column_widths.get(&col).copied()
+    }
+
+    /// Resizes a column, or puts it back to the default width with `None`.
+    pub fn set_column_width(&mut self, col: usize, width: Option<u16>) {
+        match width {
+            Some(width) => self.column_widths.insert(col, width),
+            None => self.column_widths.remove(&col),
+        };
+    }
+
+    /// The height of a row in terminal lines, if it's been resized.
+    pub fn row_height(&self, row: usize) -> Option<u16> {
+        self.row_heights.get(&row).copied()
+    }
+
+    /// Resizes a row, or puts it back to the default height with `None`.
+    pub fn set_row_height(&mut self, row: usize, height: Option<u16>) {
+        match height {
+            Some(height) => self.row_heights.insert(row, height),
+            None => self.row_heights.remove(&row),
+        };
+    }
+
     pub fn insert_row(&mut self, at: usize) {
+        self.insert_rows(at, 1);
+    }
+
+    /// Inserts `count` empty rows at `at`, shifting the rows from there on down and recalculating
+    /// once.
+    pub fn insert_rows(&mut self, at: usize, count: usize) {
+        let edit = StructuralEdit::InsertRows { at, count };
+
         for row_idx in (at..self.max_row.saturating_add(1)).rev() {
             if let Some(row) = self.rows.remove(&row_idx) {
-                self.rows.insert(row_idx.saturating_add(1), row);
+                self.rows.insert(row_idx.saturating_add(count), row);
             }
         }
 
-        self.rows.insert(at, Row::default());
-        self.max_row = self.max_row.saturating_add(1);
+        self.max_row = self.max_row.saturating_add(count);
+
+        self.row_heights = std::mem::take(&mut self.row_heights)
+            .into_iter()
+            .filter_map(|(row, height)| Some((edit.apply_row(row)?, height)))
+            .collect();
+
+        self.finish_structural_edit(edit);
     }
 
     pub fn insert_column(&mut self, at: usize) {
+        self.insert_columns(at, 1);
+    }
+
+    /// Inserts `count` empty columns at `at`, shifting the columns from there on right and
+    /// recalculating once.
+    pub fn insert_columns(&mut self, at: usize, count: usize) {
+        let edit = StructuralEdit::InsertColumns { at, count };
+
         for row in self.rows.values_mut() {
-            row.insert_column(at);
+            row.insert_columns(at, count);
         }
+
+        self.column_widths = std::mem::take(&mut self.column_widths)
+            .into_iter()
+            .filter_map(|(col, width)| Some((edit.apply_column(col)?, width)))
+            .collect();
+
+        self.finish_structural_edit(edit);
     }
 
-    pub fn clear_cell(&mut self, pos: &Position) {
-        if let Some(row) = self.rows.get_mut(&pos.row) {
-            row.clear_cell(pos.col);
+    /// Replaces the text of each given cell, e.g. when pasting, then recalculates them and their
+    /// dependents together. Returns a description of the cycle if that introduced one.
+    pub fn set_cells(&mut self, cells: Vec<(Position, String)>) -> Option<String> {
+        let positions: Vec<Position> = cells.iter().map(|(pos, _)| *pos).collect();
+
+        for (pos, text) in cells {
+            self.rows
+                .entry(pos.row)
+                .or_default()
+                .set_cell(pos.col, text);
+            self.max_row = self.max_row.max(pos.row);
+
+            self.update_dependencies(pos);
         }
+
+        self.recalculate(positions)
+            .map(|cycle| describe_cycle(&cycle))
     }
 
-    pub fn delete_row(&mut self, row: usize) {
-        self.rows.remove(&row);
+    /// Clears every cell inside the rectangle from `start` to `end` (inclusive), recalculating
+    /// their dependents once at the end.
+    pub fn clear_range(&mut self, start: &Position, end: &Position) {
+        let positions: Vec<Position> = self
+            .cells_in_range(start, end)
+            .into_iter()
+            .map(|(pos, _)| pos)
+            .collect();
+
+        for pos in &positions {
+            if let Some(row) = self.rows.get_mut(&pos.row) {
+                row.clear_cell(pos.col);
+            }
+
+            self.update_dependencies(*pos);
+        }
 
+        self.recalculate(positions);
+    }
+
+    /// Deletes `count` rows from `start` onwards, shifting the rows below up and recalculating
+    /// once. Returns the formulas left referencing nothing but deleted cells, where they are now
+    /// with their text from before the delete, so the references can be put back if the rows
+    /// are pasted somewhere else.
+    pub fn delete_rows(&mut self, start: usize, count: usize) -> Vec<(Position, String)> {
+        let edit = StructuralEdit::DeleteRows { at: start, count };
         let mut new_max = self.max_row;
 
-        for row_idx in row..self.max_row.saturating_add(1) {
+        for row_idx in start..self.max_row.saturating_add(1) {
             if let Some(row) = self.rows.remove(&row_idx) {
-                self.rows.insert(row_idx.saturating_sub(1), row);
-                new_max = row_idx;
+                if let Some(new_idx) = edit.apply_row(row_idx) {
+                    self.rows.insert(new_idx, row);
+                    new_max = new_idx;
+                }
             }
         }
 
         self.max_row = new_max;
+
+        self.row_heights = std::mem::take(&mut self.row_heights)
+            .into_iter()
+            .filter_map(|(row_idx, height)| Some((edit.apply_row(row_idx)?, height)))
+            .collect();
+
+        self.finish_structural_edit(edit)
     }
 
-    pub fn delete_column(&mut self, at: usize) {
+    /// Deletes `count` columns from `start` onwards, shifting the columns to the right left and
+    /// recalculating once. Returns the formulas that lost references like `delete_rows`.
+    pub fn delete_columns(&mut self, start: usize, count: usize) -> Vec<(Position, String)> {
+        let edit = StructuralEdit::DeleteColumns { at: start, count };
+
         for row in self.rows.values_mut() {
-            row.delete_column(at);
+            row.delete_columns(start, count);
+        }
+
+        self.column_widths = std::mem::take(&mut self.column_widths)
+            .into_iter()
+            .filter_map(|(col, width)| Some((edit.apply_column(col)?, width)))
+            .collect();
+
+        self.finish_structural_edit(edit)
+    }
+
+    /// Evaluates the cell at `pos` and its dependents. Returns a description of the cycle if the
+    /// edit introduced a circular reference.
+    pub fn evaluate_current_cell(&mut self, pos: &Position) -> Option<String> {
+        self.update_dependencies(*pos);
+
+        self.recalculate([*pos]).map(|cycle| describe_cycle(&cycle))
+    }
+
+    /// Rebuilds the dependency graph from scratch and re-evaluates every formula. Used after
+    /// loading and after structural edits, which move cells out from under their references.
+    /// Formulas are only parsed again if their text changed, which makes this the path to time
+    /// when measuring evaluation speed (`:recalc`).
+    pub fn recalculate_all(&mut self) {
+        self.precedents.clear();
+        self.dependents.clear();
+        self.range_dependents.clear();
+
+        let positions = self.positions();
+
+        for pos in &positions {
+            self.update_dependencies(*pos);
+        }
+
+        // every cell is affected, so there's nothing to search for. Only formulas have to wait for
+        // other cells, so only their edges matter.
+        let edges = positions
+            .into_iter()
+            .map(|pos| {
+                let is_formula = self
+                    .get_cell(pos.col, pos.row)
+                    .is_some_and(|cell| cell.text().starts_with('='));
+                let dependents = if is_formula {
+                    self.dependents_of(&pos)
+                } else {
+                    vec![]
+                };
+
+                (pos, dependents)
+            })
+            .collect();
+
+        self.evaluate_in_order(&edges);
+    }
+
+    // Brings references and the dependency graph up to date after `edit` has moved the cells, then
+    // recalculates the formulas whose references changed along with their dependents. Returns the
+    // formulas that lost a reference, with their text from before.
+    fn finish_structural_edit(&mut self, edit: StructuralEdit) -> Vec<(Position, String)> {
+        let (changed, broken, names_changed) = self.shift_references(edit);
+
+        // formulas using a name don't mention what it points at, so there's no telling which of
+        // them moved
+        if names_changed {
+            self.recalculate_all();
+            return broken;
+        }
+
+        // the graph follows the formulas to where they are now. A formula whose text is the same
+        // still references the same cells.
+        self.dependents.clear();
+        self.range_dependents.clear();
+
+        for (pos, precedents) in std::mem::take(&mut self.precedents) {
+            if let Some(pos) = edit.apply_position(pos) {
+                self.link(pos, &precedents);
+                self.precedents.insert(pos, precedents);
+            }
+        }
+
+        for pos in &changed {
+            self.update_dependencies(*pos);
+        }
+
+        self.recalculate(changed);
+
+        broken
+    }
+
+    // Rewrites every formula and name so their references follow the cells they point at.
+    // Returns the formulas that changed, and whether any name did.
+    fn shift_references(
+        &mut self,
+        edit: StructuralEdit,
+    ) -> (Vec<Position>, Vec<(Position, String)>, bool) {
+        let mut names_changed = false;
+
+        for name in self.names.values_mut() {
+            let shifted =
+                parser::rewrite_references(&name.definition, |reference| edit.apply(reference));
+
+            // a shifted reference is still a reference, so this only fails if the definition
+            // somehow stopped parsing before
+            if let Ok(node) = parser::parse_name_definition(&shifted) {
+                name.node = node;
+            }
+
+            names_changed |= shifted != name.definition;
+            name.definition = shifted;
+        }
+
+        let mut changed = vec![];
+        let mut broken = vec![];
+
+        for pos in self.positions() {
+            let Some(cell) = self.get_mut_cell(&pos) else {
+                continue;
+            };
+
+            let Some(formula) = cell.text().strip_prefix('=') else {
+                continue;
+            };
+
+            let mut lost_reference = false;
+            let shifted = parser::rewrite_references(formula, |reference| {
+                let shifted = edit.apply(reference);
+                lost_reference |= shifted.is_none();
+
+                shifted
+            });
+
+            if shifted != formula {
+                if lost_reference {
+                    broken.push((pos, cell.text().to_string()));
+                }
+
+                cell.set_text(format!("={shifted}"));
+                changed.push(pos);
+            }
+        }
+
+        (changed, broken, names_changed)
+    }
+
+    fn positions(&self) -> Vec<Position> {
+        self.rows
+            .iter()
+            .flat_map(|(row_idx, row)| row.columns().map(|col| Position { col, row: *row_idx }))
+            .collect()
+    }
+
+    // Replaces the edges out of `pos` with whatever its current text references
+    fn update_dependencies(&mut self, pos: Position) {
+        if let Some(old_precedents) = self.precedents.remove(&pos) {
+            self.unlink(pos, &old_precedents);
+        }
+
+        let Some(Ok(formula)) = self.get_cell(pos.col, pos.row).and_then(Cell::formula) else {
+            return;
+        };
+
+        let precedents: Vec<Area> = parser::references(formula, self)
+            .into_iter()
+            .map(|(start, end)| Area { start, end })
+            .collect();
+
+        if !precedents.is_empty() {
+            self.link(pos, &precedents);
+            self.precedents.insert(pos, precedents);
+        }
+    }
+
+    // Records `formula` as a dependent of each of its precedents
+    fn link(&mut self, formula: Position, precedents: &[Area]) {
+        for area in precedents {
+            if area.is_cell() {
+                self.dependents
+                    .entry(area.start)
+                    .or_default()
+                    .insert(formula);
+                continue;
+            }
+
+            for col in area.start.col..=area.end.col {
+                self.range_dependents
+                    .entry(col)
+                    .or_default()
+                    .entry(area.start.row)
+                    .or_default()
+                    .push((area.end.row, formula));
+            }
         }
     }
 
-    pub fn evaluate_current_cell(&mut self, pos: &Position) {
-        let mut evaluated = "".to_string();
-        if let Some(current_cell) = self.get_cell(pos.col, pos.row) {
-            let text = current_cell.to_str();
-            evaluated = parser::parse(text.strip_prefix("="), self);
+    fn unlink(&mut self, formula: Position, precedents: &[Area]) {
+        for area in precedents {
+            if area.is_cell() {
+                if let Entry::Occupied(mut dependents) = self.dependents.entry(area.start) {
+                    dependents.get_mut().remove(&formula);
+
+                    if dependents.get().is_empty() {
+                        dependents.remove();
+                    }
+                }
+                continue;
+            }
+
+            for col in area.start.col..=area.end.col {
+                let Some(by_row) = self.range_dependents.get_mut(&col) else {
+                    continue;
+                };
+
+                if let Some(ranges) = by_row.get_mut(&area.start.row) {
+                    ranges.retain(|range| *range != (area.end.row, formula));
+
+                    if ranges.is_empty() {
+                        by_row.remove(&area.start.row);
+                    }
+                }
+
+                if by_row.is_empty() {
+                    self.range_dependents.remove(&col);
+                }
+            }
+        }
+    }
+
+    // The formula cells that reference `pos`, either on its own or through a range. A formula
+    // referencing it more than once is listed more than once.
+    fn dependents_of(&self, pos: &Position) -> Vec<Position> {
+        let mut dependents: Vec<Position> = self
+            .dependents
+            .get(pos)
+            .map(|dependents| dependents.iter().copied().collect())
+            .unwrap_or_default();
+
+        if let Some(by_row) = self.range_dependents.get(&pos.col) {
+            dependents.extend(
+                by_row
+                    .range(..=pos.row)
+                    .flat_map(|(_, ranges)| ranges)
+                    .filter(|(end_row, _)| *end_row >= pos.row)
+                    .map(|(_, formula)| *formula),
+            );
         }
-        
-        if evaluated.is_empty() {
+
+        dependents
+    }
+
+    // Re-evaluates `changed` and everything that transitively depends on it. Returns one of the
+    // cycles if any of them can't be ordered.
+    fn recalculate(
+        &mut self,
+        changed: impl IntoIterator<Item = Position>,
+    ) -> Option<Vec<Position>> {
+        // every affected cell, along with its dependents so they're only looked up once
+        let mut edges: HashMap<Position, Vec<Position>> = HashMap::new();
+        let mut stack: Vec<Position> = changed.into_iter().collect();
+
+        while let Some(pos) = stack.pop() {
+            if let Entry::Vacant(entry) = edges.entry(pos) {
+                let dependents = self.dependents_of(&pos);
+                stack.extend(dependents.iter().copied());
+                entry.insert(dependents);
+            }
+        }
+
+        self.evaluate_in_order(&edges)
+    }
+
+    // Evaluates every cell in `edges` in topological order (Kahn's algorithm), so each formula
+    // sees up to date values. Cells that can't be ordered are part of, or depend on, a cycle -
+    // they are set to `#CIRC!` and one of the cycles is returned.
+    fn evaluate_in_order(
+        &mut self,
+        edges: &HashMap<Position, Vec<Position>>,
+    ) -> Option<Vec<Position>> {
+        let mut in_degree: HashMap<Position, usize> = edges.keys().map(|pos| (*pos, 0)).collect();
+
+        for dependent in edges.values().flatten() {
+            if let Some(degree) = in_degree.get_mut(dependent) {
+                *degree += 1;
+            }
+        }
+
+        let mut ready: Vec<Position> = in_degree
+            .iter()
+            .filter(|(_, degree)| **degree == 0)
+            .map(|(pos, _)| *pos)
+            .collect();
+
+        while let Some(pos) = ready.pop() {
+            in_degree.remove(&pos);
+            self.evaluate_cell(pos);
+
+            for dependent in &edges[&pos] {
+                if let Some(degree) = in_degree.get_mut(dependent) {
+                    *degree -= 1;
+
+                    if *degree == 0 {
+                        ready.push(*dependent);
+                    }
+                }
+            }
+        }
+
+        let circular: HashSet<Position> = in_degree.into_keys().collect();
+        let cycle = self.find_cycle(&circular);
+
+        if let Some(cycle) = &cycle {
+            let error = CellError::new(ErrorKind::Circ, describe_cycle(cycle));
+
+            for pos in &circular {
+                if let Some(cell) = self.get_mut_cell(pos) {
+                    cell.set_evaluated(Value::Error(error.clone()));
+                }
+            }
+        }
+
+        cycle
+    }
+
+    // Every cell left over by `recalculate` still has an unevaluated precedent, so following
+    // precedents from any of them must eventually loop back on itself
+    fn find_cycle(&self, circular: &HashSet<Position>) -> Option<Vec<Position>> {
+        let mut path: Vec<Position> =
+            vec![*circular.iter().min_by_key(|pos| (pos.row, pos.col))?];
+
+        loop {
+            let current = path[path.len() - 1];
+            let precedents = self.precedents.get(&current)?;
+            let next = *circular
+                .iter()
+                .filter(|pos| precedents.iter().any(|area| area.contains(pos)))
+                .min_by_key(|pos| (pos.row, pos.col))?;
+
+            if let Some(start) = path.iter().position(|pos| *pos == next) {
+                let mut cycle = path.split_off(start);
+                cycle.push(next);
+
+                return Some(cycle);
+            }
+
+            path.push(next);
+        }
+    }
+
+    fn evaluate_cell(&mut self, pos: Position) {
+        let Some(formula) = self.get_cell(pos.col, pos.row).and_then(Cell::formula) else {
             return;
+        };
+
+        let value = parser::evaluate_formula(formula, self);
+
+        if let Some(cell) = self.get_mut_cell(&pos) {
+            cell.set_evaluated(value);
         }
+    }
+}
+
+fn describe_cycle(cycle: &[Position]) -> String {
+    let cells: Vec<String> = cycle.iter().map(ToString::to_string).collect();
+
+    format!("Circular reference: {}", cells.join(" -> "))
+}
+
+// Splits a line of the save file into its fields. Fields wrapped in double quotes may contain
+// commas, and `""` inside them is a literal quote.
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
 
-        // TODO: we know that cell exists. we can force this here
-        if let Some(current_mut_cell) = self.get_mut_cell(pos) {
-            current_mut_cell.set_evaluated(evaluated)
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if in_quotes => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            }
+            '"' if field.is_empty() => in_quotes = true,
+            ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
         }
     }
+
+    fields.push(field);
+
+    fields
+}
+
+fn escape_csv(text: &str) -> String {
+    if text.contains(',') || text.contains('"') || text.starts_with(DIRECTIVE_PREFIX) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+// Fixtures shared by the tests of every module that evaluates formulas
+#[cfg(test)]
+impl Document {
+    /// A document holding `rows` of cell text, starting at A1. Empty strings leave the cell empty.
+    pub fn from_rows(rows: &[&[&str]]) -> Self {
+        let mut document = Self::default();
+
+        document.set_cells(
+            rows.iter()
+                .enumerate()
+                .flat_map(|(row, texts)| {
+                    texts
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, text)| !text.is_empty())
+                        .map(move |(col, text)| (Position { col, row }, (*text).to_string()))
+                })
+                .collect(),
+        );
+
+        document
+    }
+
+    /// Sets cells by address, e.g. `("B2", "=A1")`, returning the cycle description if any.
+    pub fn set(&mut self, cells: &[(&str, &str)]) -> Option<String> {
+        self.set_cells(
+            cells
+                .iter()
+                .map(|(address, text)| (position(address), (*text).to_string()))
+                .collect(),
+        )
+    }
+
+    /// What the cell at an address like `B2` shows.
+    pub fn shown(&self, address: &str) -> String {
+        let pos = position(address);
+
+        self.get_cell(pos.col, pos.row)
+            .map_or_else(String::new, |cell| cell.to_str().to_string())
+    }
+
+    /// What `formula` (without its `=`) evaluates to against this document, as it'd be shown.
+    pub fn eval(&self, formula: &str) -> String {
+        parser::evaluate_formula(&parser::parse_formula(formula), self).to_string()
+    }
+}
+
+/// What `formula` (without its `=`) evaluates to on an empty sheet.
+#[cfg(test)]
+pub fn eval(formula: &str) -> String {
+    Document::default().eval(formula)
+}
+
+/// The position of an address like `B2`.
+#[cfg(test)]
+pub fn position(address: &str) -> Position {
+    let digits = address.find(|c: char| c.is_ascii_digit()).unwrap();
+    let col = parser::column_index(&address[..digits]).unwrap();
+    let row = address[digits..].parse::<usize>().unwrap() - 1;
+
+    Position { col, row }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_dependents_follow_edits_inside_the_range() {
+        let mut document = Document::default();
+        document.set(&[("A1", "1"), ("A5", "2"), ("B1", "=SUM(A1:A10)")]);
+        assert_eq!(document.shown("B1"), "3");
+
+        document.set(&[("A5", "10")]);
+        assert_eq!(document.shown("B1"), "11");
+
+        document.set(&[("A11", "100")]);
+        assert_eq!(document.shown("B1"), "11");
+    }
+
+    #[test]
+    fn huge_ranges_are_not_expanded() {
+        let mut document = Document::default();
+        document.set(&[("A1", "1"), ("A2", "2"), ("B1", "=SUM(A1:A2000000)")]);
+        assert_eq!(document.shown("B1"), "3");
+
+        document.set(&[("A1500000", "4")]);
+        assert_eq!(document.shown("B1"), "7");
+    }
+
+    #[test]
+    fn cycles_are_marked_and_described() {
+        let mut document = Document::default();
+        document.set(&[("A1", "=B1+1")]);
+
+        let cycle = document.set(&[("B1", "=A1")]);
+        assert_eq!(cycle.as_deref(), Some("Circular reference: A1 -> B1 -> A1"));
+        assert_eq!(document.shown("A1"), "#CIRC!");
+        assert_eq!(document.shown("B1"), "#CIRC!");
+
+        assert_eq!(document.set(&[("B1", "2")]), None);
+        assert_eq!(document.shown("A1"), "3");
+    }
+
+    #[test]
+    fn self_references_and_cycles_through_ranges_are_cycles() {
+        let mut document = Document::default();
+
+        assert!(document.set(&[("A1", "=A1")]).is_some());
+        assert_eq!(document.shown("A1"), "#CIRC!");
+
+        assert!(document.set(&[("B2", "=SUM(B1:B3)")]).is_some());
+        assert_eq!(document.shown("B2"), "#CIRC!");
+    }
+
+    #[test]
+    fn cells_depending_on_a_cycle_are_circular_too() {
+        let mut document = Document::default();
+        document.set(&[("C1", "=A1*2"), ("D1", "5")]);
+        document.set(&[("A1", "=B1"), ("B1", "=A1")]);
+
+        assert_eq!(document.shown("C1"), "#CIRC!");
+        assert_eq!(document.shown("D1"), "5");
+    }
+
+    #[test]
+    fn loaded_row_heights_are_at_least_the_minimum() {
+        let mut document = Document::default();
+        document.load_directive("rowheight 2 1");
+        document.load_directive("rowheight 3 7");
+
+        assert_eq!(document.row_height(1), Some(constants::MIN_CELL_HEIGHT));
+        assert_eq!(document.row_height(2), Some(7));
+    }
+
+    #[test]
+    fn row_heights_follow_inserted_and_deleted_rows() {
+        let mut document = Document::default();
+        document.set_row_height(2, Some(5));
+        document.set_row_height(4, Some(6));
+
+        document.insert_row(0);
+        assert_eq!(document.row_height(3), Some(5));
+
+        document.delete_rows(3, 1);
+        assert_eq!(document.row_height(3), None);
+        assert_eq!(document.row_height(4), Some(6));
+    }
+
+    #[test]
+    fn names_must_not_look_like_anything_else() {
+        let mut document = Document::default();
+
+        assert!(document.define_name("tax_rate.2", "A1").is_ok());
+        assert!(document.define_name("2x", "A1").is_err());
+        assert!(document.define_name("my name", "A1").is_err());
+        assert!(document.define_name("B2", "A1").is_err());
+        assert!(document.define_name("true", "A1").is_err());
+        assert!(document.define_name("other", "A1+").is_err());
+        assert!(document.define_name("other", "TAX_RATE.2").is_err());
+        assert_eq!(document.name("other"), None);
+    }
+
+    #[test]
+    fn names_resolve_in_formulas() {
+        let mut document = Document::from_rows(&[
+            &["1", "=Sales*2"],
+            &["2", "=SUM(sales)"],
+            &["3", "=G+1"],
+            &["", "=UNKNOWN+1"],
+        ]);
+        document.define_name("sales", "A1:A3").unwrap();
+        document.define_name("g", "9.81").unwrap();
+
+        assert_eq!(document.shown("B2"), "6");
+        assert_eq!(document.shown("B3"), "10.81");
+        assert_eq!(document.shown("B4"), "#NAME?");
+        // a range only makes sense where a function expects one
+        assert_eq!(document.shown("B1"), "#VALUE!");
+
+        // redefining a name recalculates the formulas using it
+        document.define_name("SALES", "A1:A2").unwrap();
+        assert_eq!(document.shown("B2"), "3");
+    }
+
+    #[test]
+    fn structural_edits_move_names() {
+        let mut document = Document::from_rows(&[&["1"], &["2"], &["=RATE*10"]]);
+        document.define_name("rate", "A2").unwrap();
+        assert_eq!(document.shown("A3"), "20");
+
+        document.insert_row(0);
+        assert_eq!(document.name("RATE"), Some("A3"));
+        assert_eq!(document.shown("A4"), "20");
+
+        document.delete_rows(2, 1);
+        assert_eq!(document.name("RATE"), Some("#REF!"));
+        assert_eq!(document.shown("A3"), "#REF!");
+    }
+
+    #[test]
+    fn names_are_saved_and_loaded() {
+        let path = std::env::temp_dir().join(format!("names-{}.csv", std::process::id()));
+        let filename = path.to_string_lossy().to_string();
+
+        let mut document = Document::from(filename.clone()).unwrap();
+        document.set(&[("A1", "4"), ("A2", "=Side*Side")]);
+        document.define_name("side", "$A$1").unwrap();
+        document.save().unwrap();
+
+        let loaded = Document::from(filename).unwrap();
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(loaded.name("SIDE"), Some("$A$1"));
+        assert_eq!(loaded.shown("A2"), "16");
+    }
+
+    #[test]
+    fn structural_edits_move_formulas_and_their_dependencies() {
+        let mut document = Document::from_rows(&[&["1", "=A3*2"], &["2"], &["=A1+A2"]]);
+        document.set(&[("C10", "=SUM(A1:A2)")]);
+
+        document.insert_row(1);
+        assert_eq!(document.get_cell(0, 3).unwrap().text(), "=A1+A3");
+        assert_eq!(document.get_cell(1, 0).unwrap().text(), "=A4*2");
+        assert_eq!(document.shown("A4"), "3");
+
+        // C11 moved without its text changing, and still follows A1
+        document.set(&[("A1", "5")]);
+        assert_eq!(document.shown("A4"), "7");
+        assert_eq!(document.shown("B1"), "14");
+        assert_eq!(document.shown("C11"), "7");
+
+        document.delete_rows(0, 1);
+        assert_eq!(document.get_cell(0, 2).unwrap().text(), "=#REF!+A2");
+        assert_eq!(document.shown("A3"), "#REF!");
+        assert_eq!(document.shown("C10"), "2");
+
+        document.set(&[("A2", "4")]);
+        assert_eq!(document.shown("C10"), "4");
+    }
+
+    #[test]
+    fn inserting_several_rows_and_columns() {
+        let mut document = Document::from_rows(&[&["1", "=A1+A2"], &["2"]]);
+        document.set_row_height(1, Some(5));
+
+        document.insert_rows(1, 2);
+        assert_eq!(document.get_cell(1, 0).unwrap().text(), "=A1+A4");
+        assert_eq!(document.shown("B1"), "3");
+        assert_eq!(document.row_height(3), Some(5));
+        assert_eq!(document.max_row(), 3);
+
+        document.insert_columns(0, 3);
+        assert_eq!(document.get_cell(4, 0).unwrap().text(), "=D1+D4");
+        assert_eq!(document.shown("E1"), "3");
+    }
+
+    #[test]
+    fn deleting_several_rows_and_columns() {
+        let mut document = Document::from_rows(&[
+            &["1", "10", "100", "=SUM(A1:A5)"],
+            &["2"],
+            &["3"],
+            &["4"],
+            &["5", "", "", "=A5+C1"],
+        ]);
+        document.set_row_height(4, Some(5));
+
+        document.delete_rows(1, 3);
+        assert_eq!(document.get_cell(3, 0).unwrap().text(), "=SUM(A1:A2)");
+        assert_eq!(document.get_cell(3, 1).unwrap().text(), "=A2+C1");
+        assert_eq!(document.shown("D1"), "6");
+        assert_eq!(document.shown("D2"), "105");
+        assert_eq!(document.row_height(1), Some(5));
+        assert_eq!(document.max_row(), 1);
+
+        document.delete_columns(1, 2);
+        assert_eq!(document.get_cell(1, 1).unwrap().text(), "=A2+#REF!");
+        assert_eq!(document.shown("B1"), "6");
+        assert_eq!(document.shown("B2"), "#REF!");
+    }
 }
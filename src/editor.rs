@@ -1,14 +1,19 @@
 use crate::constants;
 use crate::document::Document;
 use crate::handlers;
+use crate::parser;
+use crate::register::{self, Register, Shape};
 use crate::ui;
 use crate::Cell;
 
+use std::collections::HashMap;
 use std::env;
+use std::fmt;
 use std::io::stdout;
 use std::time::Instant;
 
 use ratatui::backend::CrosstermBackend;
+use ratatui::layout::Rect;
 use ratatui::Terminal;
 
 #[derive(PartialEq)]
@@ -18,7 +23,14 @@ pub enum Mode {
     Command,
     SaveAs,
     Delete,
+    Yank,
     Search,
+    // selects the block of cells between `visual_anchor` and the cursor
+    Visual,
+    // selects whole rows
+    VisualRow,
+    // selects whole columns
+    VisualColumn,
 }
 
 #[derive(PartialEq)]
@@ -30,12 +42,48 @@ pub enum SearchMode {
     Error,
 }
 
-#[derive(Default)]
+// Naming a register with `"x` before a yank, delete or paste
+#[derive(PartialEq)]
+pub enum RegisterPrefix {
+    None,
+    // `"` was pressed and the name comes next
+    Awaiting,
+    Named(char),
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Position {
     pub col: usize,
     pub row: usize,
 }
 
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", parser::column_name(self.col), self.row + 1)
+    }
+}
+
+/// The part of the sheet selected in one of the visual modes. Whole rows and columns are kept
+/// apart from blocks of cells, since operations like delete treat them differently.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Selection {
+    Cells { start: Position, end: Position },
+    Rows { start: usize, end: usize },
+    Columns { start: usize, end: usize },
+}
+
+impl Selection {
+    pub fn contains(&self, pos: Position) -> bool {
+        match *self {
+            Self::Cells { start, end } => {
+                (start.row..=end.row).contains(&pos.row) && (start.col..=end.col).contains(&pos.col)
+            }
+            Self::Rows { start, end } => (start..=end).contains(&pos.row),
+            Self::Columns { start, end } => (start..=end).contains(&pos.col),
+        }
+    }
+}
+
 pub struct StatusMessage {
     pub text: String,
     time: Instant,
@@ -73,8 +121,25 @@ pub struct Editor {
     pub command: Cell,
     pub status_message: StatusMessage,
     pub viewbox_anchor: Position,
+    // the corner of a visual mode selection opposite the cursor
+    pub visual_anchor: Position,
+    // the room the cells have on screen in terminal columns and lines, updated whenever the
+    // terminal is drawn
+    pub viewbox_width: u16,
+    pub viewbox_height: u16,
+    // width of the columns that haven't been resized, in characters
+    pub cell_width: u16,
+    // height of the rows that haven't been resized, in lines
+    pub cell_height: u16,
     pub search_text: Cell,
     pub search_mode: SearchMode,
+    // show every cell's raw text (formulas) instead of its evaluated value
+    pub show_formulas: bool,
+    // one line per row with thin column separators, rather than a box around every cell
+    pub dense: bool,
+    // filled by yanks and deletes, and read by pastes
+    registers: HashMap<char, Register>,
+    pub register_prefix: RegisterPrefix,
 }
 
 impl Editor {
@@ -83,7 +148,7 @@ impl Editor {
         let mut initial_status = StatusMessage::empty();
 
         let document = if let Some(filename) = args.get(1) {
-            let doc = Document::from(filename.to_string());
+            let doc = Document::from(filename.clone());
 
             match doc {
                 Ok(doc) => doc,
@@ -104,14 +169,27 @@ impl Editor {
             command: Cell::default(),
             status_message: initial_status,
             viewbox_anchor: Position::default(),
+            visual_anchor: Position::default(),
+            viewbox_width: 1,
+            viewbox_height: 1,
+            cell_width: constants::CELL_WIDTH,
+            cell_height: constants::CELL_HEIGHT,
             search_text: Cell::default(),
             search_mode: SearchMode::None,
+            show_formulas: false,
+            dense: false,
+            registers: HashMap::new(),
+            register_prefix: RegisterPrefix::None,
         }
     }
 
     pub fn run(&mut self) -> Result<(), std::io::Error> {
         let mut terminal = Terminal::new(CrosstermBackend::new(stdout())).unwrap();
         loop {
+            if let Ok(size) = terminal.size() {
+                self.resize(size);
+            }
+
             let _ = terminal.draw(|frame| ui::draw(frame, self));
 
             if crossterm::event::poll(std::time::Duration::from_millis(constants::POLL_TIME))? {
@@ -123,7 +201,11 @@ impl Editor {
                             Mode::Command => handlers::handle_command_mode_press(self, key.code),
                             Mode::SaveAs => handlers::handle_save_as_mode_press(self, key.code),
                             Mode::Delete => handlers::handle_delete_mode_press(self, key.code),
+                            Mode::Yank => handlers::handle_yank_mode_press(self, key.code),
                             Mode::Search => handlers::handle_search_mode_press(self, key.code),
+                            Mode::Visual | Mode::VisualRow | Mode::VisualColumn => {
+                                handlers::handle_visual_mode_press(self, key.code, key.modifiers);
+                            }
                         }
                     }
                 }
@@ -141,6 +223,225 @@ impl Editor {
         Ok(())
     }
 
+    /// The cells selected in the current visual mode, if any.
+    pub fn selection(&self) -> Option<Selection> {
+        let (anchor, cursor) = (self.visual_anchor, self.cursor_position);
+        let rows = (anchor.row.min(cursor.row), anchor.row.max(cursor.row));
+        let cols = (anchor.col.min(cursor.col), anchor.col.max(cursor.col));
+
+        match self.mode {
+            Mode::Visual => Some(Selection::Cells {
+                start: Position {
+                    col: cols.0,
+                    row: rows.0,
+                },
+                end: Position {
+                    col: cols.1,
+                    row: rows.1,
+                },
+            }),
+            Mode::VisualRow => Some(Selection::Rows {
+                start: rows.0,
+                end: rows.1,
+            }),
+            Mode::VisualColumn => Some(Selection::Columns {
+                start: cols.0,
+                end: cols.1,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Starts selecting from the cursor in one of the visual modes, or goes back to normal mode if
+    /// it's the one already in use.
+    pub fn toggle_visual_mode(&mut self, mode: Mode) {
+        if self.mode == mode {
+            self.mode = Mode::Normal;
+            return;
+        }
+
+        if self.selection().is_none() {
+            self.visual_anchor = self.cursor_position;
+        }
+
+        self.mode = mode;
+    }
+
+    /// Clears the selected cells, or deletes the selected rows or columns, and leaves visual mode
+    /// with the cursor at the start of where the selection was.
+    pub fn delete_selection(&mut self) {
+        let Some(selection) = self.selection() else {
+            return;
+        };
+
+        self.delete(selection);
+
+        match selection {
+            Selection::Cells { start, .. } => self.cursor_position = start,
+            Selection::Rows { start, .. } => self.cursor_position.row = start,
+            Selection::Columns { start, .. } => self.cursor_position.col = start,
+        }
+
+        self.mode = Mode::Normal;
+        self.move_viewbox();
+    }
+
+    /// Picks the register for the next yank, delete or paste, after a `"`.
+    pub fn select_register(&mut self, key: crossterm::event::KeyCode) {
+        self.register_prefix = RegisterPrefix::None;
+
+        match key {
+            crossterm::event::KeyCode::Char(c)
+                if c.is_ascii_lowercase() || c == register::UNNAMED =>
+            {
+                self.register_prefix = RegisterPrefix::Named(c);
+            }
+            crossterm::event::KeyCode::Esc => {}
+            _ => self.status_message = StatusMessage::from("Registers are named a to z"),
+        }
+    }
+
+    // The register named for this yank, delete or paste, which only applies to the one
+    fn take_register_name(&mut self) -> Option<char> {
+        match std::mem::replace(&mut self.register_prefix, RegisterPrefix::None) {
+            RegisterPrefix::Named(name) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Copies the selected cells' text into the unnamed register, and the one named with `"` if
+    /// there is one.
+    pub fn yank(&mut self, selection: Selection) {
+        let register = Register::from(&self.document, selection);
+
+        self.store(register);
+    }
+
+    /// Yanks `selection`, then clears its cells or deletes its rows or columns. Formulas left with
+    /// broken references are remembered in the register, so pasting it moves the rows or columns
+    /// rather than copying them.
+    pub fn delete(&mut self, selection: Selection) {
+        let mut register = Register::from(&self.document, selection);
+
+        match selection {
+            Selection::Cells { start, end } => self.document.clear_range(&start, &end),
+            Selection::Rows { start, end } => {
+                register.broken = self.document.delete_rows(start, end - start + 1);
+            }
+            Selection::Columns { start, end } => {
+                register.broken = self.document.delete_columns(start, end - start + 1);
+            }
+        }
+
+        if !register.broken.is_empty() {
+            self.status_message = StatusMessage::from(format!(
+                "{} formulas referenced the deleted cells, paste them back to fix the references",
+                register.broken.len()
+            ));
+        }
+
+        self.store(register);
+    }
+
+    // Puts `register` in the register picked with `"`, if any, and the unnamed one
+    fn store(&mut self, register: Register) {
+        if let Some(name) = self.take_register_name() {
+            self.registers.insert(name, register.clone());
+        }
+
+        self.registers.insert(register::UNNAMED, register);
+    }
+
+    /// Yanks the visual mode selection and goes back to normal mode at its start.
+    pub fn yank_selection(&mut self) {
+        let Some(selection) = self.selection() else {
+            return;
+        };
+
+        self.yank(selection);
+
+        match selection {
+            Selection::Cells { start, .. } => self.cursor_position = start,
+            Selection::Rows { start, .. } => self.cursor_position.row = start,
+            Selection::Columns { start, .. } => self.cursor_position.col = start,
+        }
+
+        self.mode = Mode::Normal;
+        self.move_viewbox();
+    }
+
+    /// Pastes a register like vim: cells overwrite the block starting at the cursor, while rows
+    /// and columns are inserted after the cursor's, or before it with `before`.
+    pub fn paste(&mut self, before: bool) {
+        let name = self.take_register_name().unwrap_or(register::UNNAMED);
+        let Some(register) = self.registers.get(&name) else {
+            self.status_message = StatusMessage::from(format!("Nothing in register {name}"));
+            return;
+        };
+        let (shape, height, width) = (register.shape, register.height, register.width);
+
+        let target = match shape {
+            Shape::Cells => self.cursor_position,
+            Shape::Rows => Position {
+                col: 0,
+                row: self.cursor_position.row + usize::from(!before),
+            },
+            Shape::Columns => Position {
+                col: self.cursor_position.col + usize::from(!before),
+                row: 0,
+            },
+        };
+        let mut cells = register.cells_at(target);
+
+        match shape {
+            Shape::Cells => self.document.clear_range(
+                &target,
+                &Position {
+                    col: target.col.saturating_add(width - 1),
+                    row: target.row.saturating_add(height - 1),
+                },
+            ),
+            Shape::Rows => {
+                self.document.insert_rows(target.row, height);
+                self.cursor_position.row = target.row;
+            }
+            Shape::Columns => {
+                self.document.insert_columns(target.col, width);
+                self.cursor_position.col = target.col;
+            }
+        }
+
+        // only now that the rows or columns are back can references to them be restored
+        let (restored, edited) = self.registers[&name].restored_references(&self.document, target);
+
+        if !restored.is_empty() || edited > 0 {
+            for register in self.registers.values_mut() {
+                register.broken.clear();
+            }
+        }
+
+        if edited > 0 {
+            self.status_message = StatusMessage::from(format!(
+                "{edited} formulas were edited after the delete, their references stay broken"
+            ));
+        }
+
+        cells.extend(restored);
+
+        if let Some(message) = self.document.set_cells(cells) {
+            self.status_message = StatusMessage::from(message);
+        }
+
+        self.move_viewbox();
+    }
+
+    /// Fits the viewbox to a terminal of the given size, keeping the cursor in view.
+    pub fn resize(&mut self, size: Rect) {
+        (self.viewbox_width, self.viewbox_height) = ui::viewbox_size(self, size);
+
+        self.move_viewbox();
+    }
+
     pub fn move_viewbox(&mut self) {
         if self.cursor_position.row < self.viewbox_anchor.row {
             // move viewbox up
@@ -152,35 +453,61 @@ impl Editor {
             self.viewbox_anchor.col = self.cursor_position.col;
         }
 
-        if self
-            .viewbox_anchor
-            .row
-            .saturating_add(usize::from(constants::SHEET_VIEWBOX_HEIGHT))
-            <= self.cursor_position.row
+        let mut height: usize = (self.viewbox_anchor.row..=self.cursor_position.row)
+            .map(|row| usize::from(ui::row_span(self, row)))
+            .sum();
+
+        while height > usize::from(self.viewbox_height)
+            && self.viewbox_anchor.row < self.cursor_position.row
         {
-            // move viewbox down
-            self.viewbox_anchor.row = self
-                .cursor_position
-                .row
-                .saturating_sub(usize::from(constants::SHEET_VIEWBOX_HEIGHT) - 1);
-        }
-
-        if self
-            .viewbox_anchor
-            .col
-            .saturating_add(usize::from(constants::SHEET_VIEWBOX_WIDTH))
-            <= self.cursor_position.col
+            // move viewbox down, until every row up to the cursor fits
+            height -= usize::from(ui::row_span(self, self.viewbox_anchor.row));
+            self.viewbox_anchor.row += 1;
+        }
+
+        let mut width: usize = (self.viewbox_anchor.col..=self.cursor_position.col)
+            .map(|col| usize::from(ui::column_span(self, col)))
+            .sum();
+
+        while width > usize::from(self.viewbox_width)
+            && self.viewbox_anchor.col < self.cursor_position.col
         {
-            // move viewbox right
-            self.viewbox_anchor.col = self
-                .cursor_position
-                .col
-                .saturating_sub(usize::from(constants::SHEET_VIEWBOX_WIDTH) - 1);
+            // move viewbox right, until every column up to the cursor fits
+            width -= usize::from(ui::column_span(self, self.viewbox_anchor.col));
+            self.viewbox_anchor.col += 1;
+        }
+    }
+
+    /// The width of a column in characters.
+    pub fn column_width(&self, col: usize) -> u16 {
+        self.document.column_width(col).unwrap_or(self.cell_width)
+    }
+
+    pub fn set_column_width(&mut self, width: u16) {
+        self.document
+            .set_column_width(self.cursor_position.col, Some(width.max(1)));
+        self.move_viewbox();
+    }
+
+    /// The height of a row in terminal lines, when it isn't dense.
+    pub fn row_height(&self, row: usize) -> u16 {
+        self.document.row_height(row).unwrap_or(self.cell_height)
+    }
+
+    // Scrolls the text of the cell under the cursor so that the cell's own cursor stays in view
+    pub fn scroll_current_cell(&mut self) {
+        let width = usize::from(self.column_width(self.cursor_position.col));
+
+        if let Some(cell) = self.document.get_mut_cell(&self.cursor_position) {
+            cell.scroll_to_cursor(width);
         }
     }
 
     pub fn execute_command(&mut self) {
-        match self.command.to_str() {
+        let command = self.command.to_string().clone();
+        let (command_name, args) = command.split_once(' ').unwrap_or((&command, ""));
+
+        match command_name {
             "q" => {
                 self.should_quit = true;
             }
@@ -199,7 +526,7 @@ impl Editor {
 
                         self.status_message = StatusMessage::from(err.to_string());
                     }
-                };
+                }
             }
             "ira" => {
                 self.document.insert_row(self.cursor_position.row);
@@ -215,6 +542,33 @@ impl Editor {
                 self.document
                     .insert_column(self.cursor_position.col.saturating_add(1));
             }
+            "recalc" => {
+                let start = Instant::now();
+                self.document.recalculate_all();
+
+                self.status_message = StatusMessage::from(format!(
+                    "Recalculated every formula in {:.2?}",
+                    start.elapsed()
+                ));
+            }
+            "set" => self.set(args.trim()),
+            "colwidth" => self.colwidth(args.trim()),
+            "rowheight" => self.rowheight(args.trim()),
+            "autofit" => self.autofit(),
+            "name" => self.name(args.trim()),
+            "names" => {
+                let names: Vec<String> = self
+                    .document
+                    .names()
+                    .map(|(name, definition)| format!("{name} = {definition}"))
+                    .collect();
+
+                self.status_message = if names.is_empty() {
+                    StatusMessage::from("No names defined")
+                } else {
+                    StatusMessage::from(names.join(", "))
+                };
+            }
             _ => {
                 self.status_message = StatusMessage::from(format!(
                     "Unrecognized command: {}",
@@ -227,6 +581,147 @@ impl Editor {
         self.mode = Mode::Normal;
     }
 
+    // Vim style options: `:set <option>` turns it on, `:set no<option>` off and `:set <option>!`
+    // toggles it. Sizes are given as `:set <option>=<number>`.
+    fn set(&mut self, option: &str) {
+        if let Some((name, size)) = option.split_once('=') {
+            self.set_size(name.trim(), size.trim());
+            return;
+        }
+
+        let (name, value) = if let Some(name) = option.strip_suffix('!') {
+            (name, None)
+        } else if let Some(name) = option.strip_prefix("no") {
+            (name, Some(false))
+        } else {
+            (option, Some(true))
+        };
+
+        match name {
+            "formulas" => {
+                self.show_formulas = value.unwrap_or(!self.show_formulas);
+            }
+            "dense" => {
+                self.dense = value.unwrap_or(!self.dense);
+            }
+            _ => {
+                self.status_message = StatusMessage::from(format!("Unknown option: {option}"));
+            }
+        }
+    }
+
+    fn set_size(&mut self, name: &str, size: &str) {
+        let (option, min) = match name {
+            "cellwidth" => (&mut self.cell_width, 1),
+            "cellheight" => (&mut self.cell_height, constants::MIN_CELL_HEIGHT),
+            _ => {
+                self.status_message = StatusMessage::from(format!("Unknown option: {name}"));
+                return;
+            }
+        };
+
+        match size.parse::<u16>() {
+            Ok(size) if size >= min => *option = size,
+            _ => {
+                self.status_message =
+                    StatusMessage::from(format!("{name} must be a number of at least {min}"));
+            }
+        }
+    }
+
+    // `:colwidth <width>` resizes the cursor's column, `:colwidth` shows how wide it is
+    fn colwidth(&mut self, args: &str) {
+        let column = parser::column_name(self.cursor_position.col);
+
+        if args.is_empty() {
+            self.status_message = StatusMessage::from(format!(
+                "Column {column} is {} wide",
+                self.column_width(self.cursor_position.col)
+            ));
+            return;
+        }
+
+        match args.parse::<u16>() {
+            Ok(width) if width >= 1 => self.set_column_width(width),
+            _ => {
+                self.status_message = StatusMessage::from(format!(
+                    "Column width must be a number of at least 1, found {args}"
+                ));
+            }
+        }
+    }
+
+    // `:rowheight <height>` resizes the cursor's row, `:rowheight` shows how tall it is
+    fn rowheight(&mut self, args: &str) {
+        let row = self.cursor_position.row;
+
+        if args.is_empty() {
+            self.status_message = StatusMessage::from(format!(
+                "Row {} is {} tall",
+                row + 1,
+                self.row_height(row)
+            ));
+            return;
+        }
+
+        match args.parse::<u16>() {
+            Ok(height) if height >= constants::MIN_CELL_HEIGHT => {
+                self.document.set_row_height(row, Some(height));
+                self.move_viewbox();
+            }
+            _ => {
+                self.status_message = StatusMessage::from(format!(
+                    "Row height must be a number of at least {}, found {args}",
+                    constants::MIN_CELL_HEIGHT
+                ));
+            }
+        }
+    }
+
+    // Fits the cursor's column to its widest cell, as currently displayed. An empty column goes
+    // back to the default width.
+    fn autofit(&mut self) {
+        let col = self.cursor_position.col;
+        let widest = self
+            .document
+            .cells_in_range(
+                &Position { col, row: 0 },
+                &Position {
+                    col,
+                    row: self.document.max_row(),
+                },
+            )
+            .into_iter()
+            .map(|(_, cell)| {
+                let text = if self.show_formulas { cell.text() } else { cell.to_str() };
+
+                text.chars().count()
+            })
+            .max();
+
+        match widest {
+            Some(width) => self.set_column_width(u16::try_from(width).unwrap_or(u16::MAX)),
+            None => self.document.set_column_width(col, None),
+        }
+    }
+
+    // `:name <name> <definition>` defines a name, `:name <name>` shows what it stands for
+    fn name(&mut self, args: &str) {
+        let message = match args.split_once(' ') {
+            Some((name, definition)) => match self.document.define_name(name, definition) {
+                Ok(()) => format!("{} = {}", name.to_ascii_uppercase(), definition.trim()),
+                Err(err) => err,
+            },
+            None if args.is_empty() => String::from("Usage: name <name> <cell, range or value>"),
+            None => match self.document.name(args) {
+                Some(definition) => format!("{} = {definition}", args.to_ascii_uppercase()),
+                None => format!("Unknown name: {args}"),
+            },
+        };
+
+        self.status_message = StatusMessage::from(message);
+    }
+
     pub fn search(&mut self) {
         let search_text = self.search_text.to_string();
 
@@ -270,7 +765,7 @@ impl Editor {
 
     pub fn view(&self, col: usize, row: usize) -> String {
         if let Some(cell) = self.document.get_cell(col, row) {
-            cell.view()
+            cell.view(self.show_formulas, usize::from(self.column_width(col)))
         } else {
             String::new()
         }
@@ -0,0 +1,621 @@
+use crate::criteria;
+use crate::document::Document;
+use crate::lookup;
+use crate::parser::{self, Node};
+use crate::value::{self, CellError, ErrorKind, Value};
+
+// A single value gathered from a function's arguments. Values read out of references are kept
+// apart from values passed directly, because spreadsheets skip text and booleans in the former
+// but try to convert them in the latter (`SUM(A1:A3)` ignores "abc", `SUM("abc")` is an error).
+enum Argument {
+    Referenced(Value),
+    Direct(Value),
+}
+
+/// Calls the built-in function `name`. Arguments are passed as unevaluated nodes, so each function
+/// decides which of them to evaluate, and when.
+pub fn call(name: &str, args: &[Node], document: &Document) -> Result<Value, CellError> {
+    match name {
+        "SUM" => parser::number_value(numbers(args, document)?.iter().sum()),
+        "AVERAGE" => {
+            let numbers = numbers(args, document)?;
+
+            if numbers.is_empty() {
+                return Err(CellError::from(ErrorKind::DivZero));
+            }
+
+            #[allow(clippy::cast_precision_loss)]
+            parser::number_value(numbers.iter().sum::<f64>() / numbers.len() as f64)
+        }
+        "MIN" => Ok(Value::Number(
+            numbers(args, document)?
+                .into_iter()
+                .reduce(f64::min)
+                .unwrap_or_default(),
+        )),
+        "MAX" => Ok(Value::Number(
+            numbers(args, document)?
+                .into_iter()
+                .reduce(f64::max)
+                .unwrap_or_default(),
+        )),
+        "COUNT" => Ok(count(
+            arguments(args, document)?
+                .iter()
+                .filter(|arg| match arg {
+                    Argument::Referenced(value) => matches!(value, Value::Number(_)),
+                    Argument::Direct(value) => value.as_number().is_ok(),
+                })
+                .count(),
+        )),
+        "COUNTA" => Ok(count(arguments(args, document)?.len())),
+        "CONCAT" => Ok(Value::String(
+            arguments(args, document)?
+                .into_iter()
+                .map(|arg| match arg {
+                    Argument::Referenced(value) | Argument::Direct(value) => value.as_text(),
+                })
+                .collect::<Result<String, CellError>>()?,
+        )),
+        "AND" => Ok(Value::Boolean(logical(args, document, false)?)),
+        "OR" => Ok(Value::Boolean(logical(args, document, true)?)),
+        "VLOOKUP" | "HLOOKUP" | "INDEX" | "MATCH" | "XLOOKUP" => lookup::call(name, args, document),
+        "SUMIF" | "COUNTIF" | "AVERAGEIF" | "SUMIFS" | "COUNTIFS" | "AVERAGEIFS" => {
+            criteria::call(name, args, document)
+        }
+        "IF" => {
+            check_arity(name, args, 2, 3)?;
+
+            // only the branch that's taken is evaluated, so `IF(A1=0, 0, B1/A1)` never divides
+            // by zero
+            if parser::evaluate(&args[0], document)?.as_bool()? {
+                parser::evaluate(&args[1], document)
+            } else {
+                args.get(2).map_or(Ok(Value::Boolean(false)), |otherwise| {
+                    parser::evaluate(otherwise, document)
+                })
+            }
+        }
+        "IFERROR" => {
+            check_arity(name, args, 2, 2)?;
+
+            parser::evaluate(&args[0], document).or_else(|_| parser::evaluate(&args[1], document))
+        }
+        "IFNA" => {
+            check_arity(name, args, 2, 2)?;
+
+            match parser::evaluate(&args[0], document) {
+                Err(err) if err.kind == ErrorKind::NotAvailable => {
+                    parser::evaluate(&args[1], document)
+                }
+                result => result,
+            }
+        }
+        _ => {
+            // an unknown name is reported before its arguments can fail, so `FOO(1/0)` is
+            // `#NAME?`
+            let function = strict_function(name).ok_or_else(|| unknown_function(name))?;
+
+            let values = args
+                .iter()
+                .map(|arg| parser::evaluate(arg, document))
+                .collect::<Result<Vec<Value>, CellError>>()?;
+
+            function(name, &values)
+        }
+    }
+}
+
+// A function that gets all its arguments evaluated up front, and fails as soon as any of them is
+// an error. It's passed its own name, so related functions can share one.
+type StrictFunction = fn(&str, &[Value]) -> Result<Value, CellError>;
+
+// Looks up the strict function `name`, if there is one
+fn strict_function(name: &str) -> Option<StrictFunction> {
+    let function: StrictFunction = match name {
+        "NOT" => |name, values| {
+            check_arity(name, values, 1, 1)?;
+
+            Ok(Value::Boolean(!values[0].as_bool()?))
+        },
+        "LEN" => |name, values| {
+            check_arity(name, values, 1, 1)?;
+
+            Ok(count(values[0].as_text()?.chars().count()))
+        },
+        "LEFT" | "RIGHT" => |name, values| {
+            check_arity(name, values, 1, 2)?;
+
+            let text: Vec<char> = values[0].as_text()?.chars().collect();
+            let len = values.get(1).map_or(Ok(1), length)?.min(text.len());
+
+            Ok(Value::String(if name == "LEFT" {
+                text[..len].iter().collect()
+            } else {
+                text[text.len() - len..].iter().collect()
+            }))
+        },
+        "MID" => |name, values| {
+            check_arity(name, values, 3, 3)?;
+
+            let text = values[0].as_text()?;
+            let start = length(&values[1])?;
+
+            if start == 0 {
+                return Err(CellError::value("MID starts counting at 1"));
+            }
+
+            Ok(Value::String(
+                text.chars()
+                    .skip(start - 1)
+                    .take(length(&values[2])?)
+                    .collect(),
+            ))
+        },
+        "UPPER" => |name, values| {
+            check_arity(name, values, 1, 1)?;
+
+            Ok(Value::String(values[0].as_text()?.to_uppercase()))
+        },
+        "LOWER" => |name, values| {
+            check_arity(name, values, 1, 1)?;
+
+            Ok(Value::String(values[0].as_text()?.to_lowercase()))
+        },
+        "TRIM" => |name, values| {
+            check_arity(name, values, 1, 1)?;
+
+            Ok(Value::String(
+                values[0]
+                    .as_text()?
+                    .split_whitespace()
+                    .collect::<Vec<&str>>()
+                    .join(" "),
+            ))
+        },
+        "SUBSTITUTE" => |name, values| {
+            check_arity(name, values, 3, 4)?;
+
+            let text = values[0].as_text()?;
+            let old = values[1].as_text()?;
+            let new = values[2].as_text()?;
+
+            if old.is_empty() {
+                return Ok(Value::String(text));
+            }
+
+            let Some(instance) = values.get(3) else {
+                return Ok(Value::String(text.replace(&old, &new)));
+            };
+
+            // only replace the nth occurrence
+            let instance = length(instance)?;
+
+            Ok(Value::String(
+                match text.match_indices(&old).nth(instance.saturating_sub(1)) {
+                    Some((idx, _)) if instance > 0 => {
+                        format!("{}{new}{}", &text[..idx], &text[idx + old.len()..])
+                    }
+                    _ => text,
+                },
+            ))
+        },
+        "TEXT" => |name, values| {
+            check_arity(name, values, 2, 2)?;
+
+            let format = values[1].as_text()?;
+
+            Ok(Value::String(match values[0].as_number() {
+                Ok(num) => format_text(num, &format),
+                Err(_) => values[0].as_text()?,
+            }))
+        },
+        _ => return math_function(name),
+    };
+
+    Some(function)
+}
+
+// Looks up the numeric function `name`, which is also strict
+fn math_function(name: &str) -> Option<StrictFunction> {
+    let function: StrictFunction = match name {
+        "ABS" | "SQRT" | "EXP" | "LN" | "LOG10" | "INT" | "SIGN" | "SIN" | "COS" | "TAN"
+        | "ASIN" | "ACOS" | "ATAN" | "DEGREES" | "RADIANS" => |name, values| {
+            check_arity(name, values, 1, 1)?;
+
+            unary_math(name, values[0].as_number()?)
+        },
+        "PI" => |name, values| {
+            check_arity(name, values, 0, 0)?;
+
+            Ok(Value::Number(std::f64::consts::PI))
+        },
+        "ROUND" | "ROUNDUP" | "ROUNDDOWN" => |name, values| {
+            check_arity(name, values, 1, 2)?;
+
+            let num = values[0].as_number()?;
+            let digits = values.get(1).map_or(Ok(0.0), Value::as_number)?.trunc();
+            let scale = 10_f64.powf(digits);
+            // round away the noise first, so `ROUNDUP(0.1 + 0.2, 1)` is 0.3 and not 0.4
+            let scaled = value::round_significant(num * scale);
+
+            let rounded = match name {
+                "ROUND" => scaled.round(),
+                "ROUNDUP" => scaled.abs().ceil().copysign(scaled),
+                _ => scaled.trunc(),
+            };
+
+            parser::number_value(rounded / scale)
+        },
+        "FLOOR" | "CEILING" => |name, values| {
+            check_arity(name, values, 1, 2)?;
+
+            let num = values[0].as_number()?;
+            let significance = values.get(1).map_or(Ok(1.0), Value::as_number)?;
+
+            if significance == 0.0 {
+                return Ok(Value::Number(0.0));
+            }
+
+            if num > 0.0 && significance < 0.0 {
+                return Err(CellError::new(
+                    ErrorKind::Num,
+                    format!("{name} of a positive number needs a positive significance"),
+                ));
+            }
+
+            let multiples = value::round_significant(num / significance);
+            let multiples = if name == "FLOOR" {
+                multiples.floor()
+            } else {
+                multiples.ceil()
+            };
+
+            parser::number_value(multiples * significance)
+        },
+        "MOD" => |name, values| {
+            check_arity(name, values, 2, 2)?;
+
+            let (num, divisor) = (values[0].as_number()?, values[1].as_number()?);
+
+            if divisor == 0.0 {
+                return Err(CellError::from(ErrorKind::DivZero));
+            }
+
+            // the result takes the sign of the divisor, like other spreadsheets
+            parser::number_value(num - divisor * (num / divisor).floor())
+        },
+        "POWER" => |name, values| {
+            check_arity(name, values, 2, 2)?;
+
+            parser::number_value(values[0].as_number()?.powf(values[1].as_number()?))
+        },
+        "ATAN2" => |name, values| {
+            check_arity(name, values, 2, 2)?;
+
+            let (x, y) = (values[0].as_number()?, values[1].as_number()?);
+
+            if x == 0.0 && y == 0.0 {
+                return Err(CellError::from(ErrorKind::DivZero));
+            }
+
+            parser::number_value(y.atan2(x))
+        },
+        _ => return None,
+    };
+
+    Some(function)
+}
+
+/// The `#NAME?` error for calling a function that doesn't exist.
+pub fn unknown_function(name: &str) -> CellError {
+    CellError::new(ErrorKind::Name, format!("Unknown function: {name}"))
+}
+
+// Functions of a single number. Results outside their domain (`SQRT(-1)`, `LN(0)`) are NaN or
+// infinite, which `number_value` turns into `#NUM!`.
+fn unary_math(name: &str, num: f64) -> Result<Value, CellError> {
+    let result = match name {
+        "ABS" => num.abs(),
+        "SQRT" => num.sqrt(),
+        "EXP" => num.exp(),
+        "LN" => num.ln(),
+        "LOG10" => num.log10(),
+        "INT" => num.floor(),
+        "SIGN" if num == 0.0 => 0.0,
+        "SIGN" => num.signum(),
+        "SIN" => num.sin(),
+        "COS" => num.cos(),
+        "TAN" => num.tan(),
+        "ASIN" => num.asin(),
+        "ACOS" => num.acos(),
+        "ATAN" => num.atan(),
+        "DEGREES" => num.to_degrees(),
+        _ => num.to_radians(),
+    };
+
+    parser::number_value(result)
+}
+
+pub fn check_arity<T>(name: &str, args: &[T], min: usize, max: usize) -> Result<(), CellError> {
+    if (min..=max).contains(&args.len()) {
+        Ok(())
+    } else if min == max {
+        let plural = if min == 1 { "" } else { "s" };
+
+        Err(CellError::value(format!(
+            "{name} takes {min} argument{plural}, got {}",
+            args.len()
+        )))
+    } else {
+        Err(CellError::value(format!(
+            "{name} takes {min} to {max} arguments, got {}",
+            args.len()
+        )))
+    }
+}
+
+fn numbers(args: &[Node], document: &Document) -> Result<Vec<f64>, CellError> {
+    let mut numbers = vec![];
+
+    for arg in arguments(args, document)? {
+        match arg {
+            Argument::Referenced(Value::Number(num)) => numbers.push(num),
+            Argument::Referenced(Value::Error(err)) => return Err(err),
+            Argument::Referenced(_) => {}
+            Argument::Direct(value) => numbers.push(value.as_number()?),
+        }
+    }
+
+    Ok(numbers)
+}
+
+// Combines logical values for AND (`decisive` is false) and OR (`decisive` is true). Arguments are
+// evaluated in order, stopping at the first one equal to `decisive`, so later arguments can't
+// raise errors once the result is known.
+fn logical(args: &[Node], document: &Document, decisive: bool) -> Result<bool, CellError> {
+    let mut found = false;
+
+    for arg in args {
+        for arg in arguments(std::slice::from_ref(arg), document)? {
+            let b = match arg {
+                Argument::Referenced(Value::Number(num)) => num != 0.0,
+                Argument::Referenced(Value::Boolean(b)) => b,
+                Argument::Referenced(Value::Error(err)) => return Err(err),
+                Argument::Referenced(_) => continue,
+                Argument::Direct(value) => value.as_bool()?,
+            };
+
+            if b == decisive {
+                return Ok(b);
+            }
+
+            found = true;
+        }
+    }
+
+    if !found {
+        return Err(CellError::value("No logical values to evaluate"));
+    }
+
+    Ok(!decisive)
+}
+
+// References (single cells, ranges and names of either) are read cell by cell, skipping empty
+// cells. Anything else is evaluated.
+fn arguments(args: &[Node], document: &Document) -> Result<Vec<Argument>, CellError> {
+    let mut values = vec![];
+
+    for arg in args {
+        let Some((start, end)) = parser::reference_bounds(arg, document)? else {
+            values.push(Argument::Direct(parser::evaluate(arg, document)?));
+            continue;
+        };
+
+        for (_, cell) in document.cells_in_range(&start, &end) {
+            values.push(Argument::Referenced(cell.value().clone()));
+        }
+    }
+
+    Ok(values)
+}
+
+#[allow(clippy::cast_precision_loss)]
+pub fn count(len: usize) -> Value {
+    Value::Number(len as f64)
+}
+
+// Reads an argument used as a count or position, which can't be negative
+fn length(value: &Value) -> Result<usize, CellError> {
+    let num = value.as_number()?;
+
+    if num < 0.0 {
+        return Err(CellError::value(format!(
+            "Expected a positive number, found {value}"
+        )));
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(num.trunc() as usize)
+}
+
+// Supports the common subset of spreadsheet number formats: a literal prefix and suffix around
+// a pattern like `#,##0.00`, where `,` turns on thousands separators, the number of digits after
+// `.` sets the precision and a `%` scales the number by 100.
+fn format_text(num: f64, format: &str) -> String {
+    let is_pattern = |c: char| matches!(c, '0' | '#' | ',' | '.');
+    let Some(start) = format.find(is_pattern) else {
+        return format.to_string();
+    };
+    // only the first run of pattern characters, so a `.` in text after it stays text
+    let end = format[start..]
+        .find(|c| !is_pattern(c))
+        .map_or(format.len(), |len| start + len);
+
+    let (prefix, pattern, suffix) = (&format[..start], &format[start..end], &format[end..]);
+    let num = if suffix.contains('%') {
+        num * 100.0
+    } else {
+        num
+    };
+    let decimals = pattern.split_once('.').map_or(0, |(_, decimals)| {
+        decimals.chars().filter(|c| matches!(c, '0' | '#')).count()
+    });
+
+    let formatted = format!("{:.*}", decimals, num.abs());
+    let (integer, fraction) = formatted.split_at(formatted.find('.').unwrap_or(formatted.len()));
+
+    let integer = if pattern.contains(',') {
+        let digits: Vec<char> = integer.chars().rev().collect();
+
+        digits
+            .chunks(3)
+            .rev()
+            .map(|chunk| chunk.iter().rev().collect::<String>())
+            .collect::<Vec<String>>()
+            .join(",")
+    } else {
+        integer.to_string()
+    };
+
+    let sign = if num < 0.0 && formatted.chars().any(|c| c.is_ascii_digit() && c != '0') {
+        "-"
+    } else {
+        ""
+    };
+
+    format!("{sign}{prefix}{integer}{fraction}{suffix}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::eval;
+
+    #[test]
+    fn unknown_functions_are_reported_before_their_arguments() {
+        assert_eq!(eval("FOO(1/0)"), "#NAME?");
+        assert_eq!(eval("ABS(1/0)"), "#DIV/0!");
+    }
+
+    #[test]
+    fn strict_functions_are_looked_up_by_name() {
+        assert!(strict_function("MID").is_some());
+        assert!(strict_function("ATAN2").is_some());
+        assert!(strict_function("SUM").is_none());
+        assert!(strict_function("FOO").is_none());
+    }
+
+    #[test]
+    fn rounding() {
+        assert_eq!(eval("ROUND(2.5)"), "3");
+        assert_eq!(eval("ROUND(-2.5)"), "-3");
+        assert_eq!(eval("ROUND(1.2345, 2)"), "1.23");
+        assert_eq!(eval("ROUND(1234, -2)"), "1200");
+        assert_eq!(eval("ROUNDUP(1.21, 1)"), "1.3");
+        assert_eq!(eval("ROUNDUP(-1.21, 1)"), "-1.3");
+        assert_eq!(eval("ROUNDDOWN(1.29, 1)"), "1.2");
+        assert_eq!(eval("ROUNDDOWN(-1.29, 1)"), "-1.2");
+
+        // floating point noise doesn't push a number over to the next digit
+        assert_eq!(eval("ROUNDUP(0.1+0.2, 1)"), "0.3");
+        assert_eq!(eval("ROUNDDOWN(0.7*3, 1)"), "2.1");
+        assert_eq!(eval("ROUND(1.005, 2)"), "1.01");
+    }
+
+    #[test]
+    fn floor_and_ceiling() {
+        assert_eq!(eval("FLOOR(7, 5)"), "5");
+        assert_eq!(eval("CEILING(7, 5)"), "10");
+        assert_eq!(eval("FLOOR(2.7)"), "2");
+        assert_eq!(eval("CEILING(0.1+0.2, 0.1)"), "0.3");
+        assert_eq!(eval("FLOOR(-7, -5)"), "-5");
+        assert_eq!(eval("CEILING(-7, -5)"), "-10");
+        assert_eq!(eval("FLOOR(7, 0)"), "0");
+        assert_eq!(eval("FLOOR(7, -5)"), "#NUM!");
+        assert_eq!(eval("CEILING(7, -5)"), "#NUM!");
+    }
+
+    #[test]
+    fn mod_takes_the_sign_of_the_divisor() {
+        assert_eq!(eval("MOD(7, 3)"), "1");
+        assert_eq!(eval("MOD(-7, 3)"), "2");
+        assert_eq!(eval("MOD(7, -3)"), "-2");
+        assert_eq!(eval("MOD(-7, -3)"), "-1");
+        assert_eq!(eval("MOD(7, 0)"), "#DIV/0!");
+    }
+
+    #[test]
+    fn results_outside_the_domain_are_num_errors() {
+        assert_eq!(eval("SQRT(-1)"), "#NUM!");
+        assert_eq!(eval("LN(0)"), "#NUM!");
+        assert_eq!(eval("LOG10(-1)"), "#NUM!");
+        assert_eq!(eval("ASIN(2)"), "#NUM!");
+        assert_eq!(eval("POWER(-8, 0.5)"), "#NUM!");
+        assert_eq!(eval("ATAN2(0, 0)"), "#DIV/0!");
+        assert_eq!(eval("SQRT(16)"), "4");
+        assert_eq!(eval("SIGN(-0.5)"), "-1");
+    }
+
+    #[test]
+    fn math_arity() {
+        assert_eq!(eval("ABS()"), "#VALUE!");
+        assert_eq!(eval("ABS(1, 2)"), "#VALUE!");
+        assert_eq!(eval("PI(1)"), "#VALUE!");
+        assert_eq!(eval("ROUND(1, 2, 3)"), "#VALUE!");
+        assert_eq!(eval("MOD(1)"), "#VALUE!");
+        let err = strict_function("MOD").unwrap()("MOD", &[Value::Number(1.0)]).err();
+        assert_eq!(
+            err.map(|err| err.message).as_deref(),
+            Some("MOD takes 2 arguments, got 1")
+        );
+    }
+
+    #[test]
+    fn aggregates_skip_text_and_booleans_in_references() {
+        let document = Document::from_rows(&[&["1", "abc", "TRUE", "", "2"]]);
+
+        assert_eq!(document.eval("SUM(A1:E1)"), "3");
+        assert_eq!(document.eval("AVERAGE(A1:E1)"), "1.5");
+        assert_eq!(document.eval("COUNT(A1:E1)"), "2");
+        assert_eq!(document.eval("COUNTA(A1:E1)"), "4");
+        assert_eq!(document.eval("MAX(B1:D1)"), "0");
+        assert_eq!(document.eval("AVERAGE(D1)"), "#DIV/0!");
+    }
+
+    #[test]
+    fn aggregates_convert_values_passed_directly() {
+        assert_eq!(eval("SUM(\"3\", TRUE)"), "4");
+        assert_eq!(eval("SUM(\"abc\")"), "#VALUE!");
+        assert_eq!(eval("COUNT(\"3\", \"x\", TRUE)"), "2");
+        assert_eq!(eval("COUNTA(1, \"\")"), "2");
+    }
+
+    #[test]
+    fn text_formats() {
+        assert_eq!(eval("TEXT(1234.567, \"#,##0.00\")"), "1,234.57");
+        assert_eq!(eval("TEXT(-0.001, \"0.00\")"), "0.00");
+        assert_eq!(eval("TEXT(0.25, \"0%\")"), "25%");
+        assert_eq!(eval("TEXT(5, \"$0\")"), "$5");
+        assert_eq!(eval("TEXT(5, \"0 units.\")"), "5 units.");
+        assert_eq!(eval("TEXT(\"abc\", \"0\")"), "abc");
+    }
+
+    #[test]
+    fn substitute() {
+        assert_eq!(eval("SUBSTITUTE(\"a-b-c\", \"-\", \"+\")"), "a+b+c");
+        assert_eq!(eval("SUBSTITUTE(\"a-b-c\", \"-\", \"+\", 2)"), "a-b+c");
+        assert_eq!(eval("SUBSTITUTE(\"a-b-c\", \"-\", \"+\", 3)"), "a-b-c");
+        assert_eq!(eval("SUBSTITUTE(\"abc\", \"\", \"x\")"), "abc");
+    }
+
+    #[test]
+    fn text_slicing_counts_characters() {
+        assert_eq!(eval("LEFT(\"héllo\", 2)"), "hé");
+        assert_eq!(eval("LEFT(\"abc\")"), "a");
+        assert_eq!(eval("RIGHT(\"héllo\", 10)"), "héllo");
+        assert_eq!(eval("MID(\"héllo\", 2, 3)"), "éll");
+        assert_eq!(eval("MID(\"abc\", 0, 1)"), "#VALUE!");
+        assert_eq!(eval("LEN(\"héllo\")"), "5");
+    }
+}
@@ -1,8 +1,18 @@
 use crate::cell::Cell;
 use crate::editor::Mode;
-use crate::editor::{Editor, SearchMode, StatusMessage};
+use crate::editor::{Editor, RegisterPrefix, SearchMode, Selection, StatusMessage};
 
 pub fn handle_normal_mode_press(editor: &mut Editor, key: crossterm::event::KeyCode, mods: crossterm::event::KeyModifiers) {
+    if editor.register_prefix == RegisterPrefix::Awaiting {
+        editor.select_register(key);
+        return;
+    }
+
+    // a named register only applies to the yank, delete or paste right after it
+    if !matches!(key, crossterm::event::KeyCode::Char('y' | 'd' | 'p' | 'P')) {
+        editor.register_prefix = RegisterPrefix::None;
+    }
+
     match key {
         crossterm::event::KeyCode::Left
         | crossterm::event::KeyCode::Right
@@ -10,6 +20,7 @@ pub fn handle_normal_mode_press(editor: &mut Editor, key: crossterm::event::KeyC
             if mods.contains(crossterm::event::KeyModifiers::CONTROL) {
                 if let Some(cell) = editor.document.get_mut_cell(&editor.cursor_position) {
                     cell.move_cursor(key);
+                    editor.scroll_current_cell();
                 } else {
                     editor.move_cursor(key);
                 }
@@ -27,11 +38,16 @@ pub fn handle_normal_mode_press(editor: &mut Editor, key: crossterm::event::KeyC
             editor.mode = Mode::Insert;
 
             if let Some(current_cell) = editor.document.get_mut_cell(&editor.cursor_position) {
-                current_cell.clear_evaluated(key == crossterm::event::KeyCode::Char('a'))
+                current_cell.clear_evaluated(key == crossterm::event::KeyCode::Char('a'));
+                editor.scroll_current_cell();
             }
         }
         crossterm::event::KeyCode::Char(':') => editor.mode = Mode::Command,
         crossterm::event::KeyCode::Char('d') => editor.mode = Mode::Delete,
+        crossterm::event::KeyCode::Char('y') => editor.mode = Mode::Yank,
+        crossterm::event::KeyCode::Char('p') => editor.paste(false),
+        crossterm::event::KeyCode::Char('P') => editor.paste(true),
+        crossterm::event::KeyCode::Char('"') => editor.register_prefix = RegisterPrefix::Awaiting,
         crossterm::event::KeyCode::Char('o') => {
             editor.command = Cell::from("irb".to_string());
             editor.execute_command();
@@ -44,6 +60,18 @@ pub fn handle_normal_mode_press(editor: &mut Editor, key: crossterm::event::KeyC
             editor.mode = Mode::Insert;
         }
         crossterm::event::KeyCode::Char('/') => editor.mode = Mode::Search,
+        crossterm::event::KeyCode::Char('v') if mods.contains(crossterm::event::KeyModifiers::CONTROL) => {
+            editor.toggle_visual_mode(Mode::VisualColumn);
+        }
+        crossterm::event::KeyCode::Char('v') => editor.toggle_visual_mode(Mode::Visual),
+        crossterm::event::KeyCode::Char('V') => editor.toggle_visual_mode(Mode::VisualRow),
+        crossterm::event::KeyCode::Char('`') => editor.show_formulas = !editor.show_formulas,
+        crossterm::event::KeyCode::Char('<') => {
+            editor.set_column_width(editor.column_width(editor.cursor_position.col).saturating_sub(1));
+        }
+        crossterm::event::KeyCode::Char('>') => {
+            editor.set_column_width(editor.column_width(editor.cursor_position.col).saturating_add(1));
+        }
         crossterm::event::KeyCode::Esc => editor.mode = Mode::Normal,
         _ => {}
     }
@@ -54,7 +82,9 @@ pub fn handle_insert_mode_press(editor: &mut Editor, key: crossterm::event::KeyC
         crossterm::event::KeyCode::Esc => {
             editor.mode = Mode::Normal;
 
-            editor.document.evaluate_current_cell(&editor.cursor_position);
+            if let Some(message) = editor.document.evaluate_current_cell(&editor.cursor_position) {
+                editor.status_message = StatusMessage::from(message);
+            }
         }
         crossterm::event::KeyCode::Char(c) => {
             editor.document.insert_at(&editor.cursor_position, c);
@@ -71,6 +101,44 @@ pub fn handle_insert_mode_press(editor: &mut Editor, key: crossterm::event::KeyC
         }
         _ => {}
     }
+
+    editor.scroll_current_cell();
+}
+
+pub fn handle_visual_mode_press(editor: &mut Editor, key: crossterm::event::KeyCode, mods: crossterm::event::KeyModifiers) {
+    if editor.register_prefix == RegisterPrefix::Awaiting {
+        editor.select_register(key);
+        return;
+    }
+
+    if !matches!(key, crossterm::event::KeyCode::Char('y' | 'd' | 'x') | crossterm::event::KeyCode::Delete) {
+        editor.register_prefix = RegisterPrefix::None;
+    }
+
+    match key {
+        crossterm::event::KeyCode::Left
+        | crossterm::event::KeyCode::Right
+        | crossterm::event::KeyCode::Down
+        | crossterm::event::KeyCode::Up
+        | crossterm::event::KeyCode::Char('h' | 'j' | 'k' | 'l') => {
+            editor.move_cursor(key);
+        }
+        crossterm::event::KeyCode::Char('v') if mods.contains(crossterm::event::KeyModifiers::CONTROL) => {
+            editor.toggle_visual_mode(Mode::VisualColumn);
+        }
+        crossterm::event::KeyCode::Char('v') => editor.toggle_visual_mode(Mode::Visual),
+        crossterm::event::KeyCode::Char('V') => editor.toggle_visual_mode(Mode::VisualRow),
+        // jump to the other end of the selection, like vim
+        crossterm::event::KeyCode::Char('o') => {
+            std::mem::swap(&mut editor.visual_anchor, &mut editor.cursor_position);
+            editor.move_viewbox();
+        }
+        crossterm::event::KeyCode::Char('d' | 'x') | crossterm::event::KeyCode::Delete => editor.delete_selection(),
+        crossterm::event::KeyCode::Char('y') => editor.yank_selection(),
+        crossterm::event::KeyCode::Char('"') => editor.register_prefix = RegisterPrefix::Awaiting,
+        crossterm::event::KeyCode::Esc => editor.mode = Mode::Normal,
+        _ => {}
+    }
 }
 
 pub fn handle_command_mode_press(editor: &mut Editor, key: crossterm::event::KeyCode) {
@@ -79,11 +147,7 @@ pub fn handle_command_mode_press(editor: &mut Editor, key: crossterm::event::Key
             editor.command = Cell::default();
             editor.mode = Mode::Normal;
         }
-        crossterm::event::KeyCode::Char(c) => {
-            if !c.is_control() {
-                editor.command.insert(c);
-            }
-        }
+        crossterm::event::KeyCode::Char(c) if !c.is_control() => editor.command.insert(c),
         crossterm::event::KeyCode::Enter => editor.execute_command(),
         crossterm::event::KeyCode::Left | crossterm::event::KeyCode::Right => {
             editor.command.move_cursor(key);
@@ -96,18 +160,38 @@ pub fn handle_command_mode_press(editor: &mut Editor, key: crossterm::event::Key
 }
 
 pub fn handle_delete_mode_press(editor: &mut Editor, key: crossterm::event::KeyCode) {
+    let (col, row) = (editor.cursor_position.col, editor.cursor_position.row);
+
+    // whatever's deleted goes into a register first, so it can be pasted somewhere else
     match key {
-        crossterm::event::KeyCode::Char(' ') => editor.document.clear_cell(&editor.cursor_position),
-        crossterm::event::KeyCode::Char('r') => {
-            editor.document.delete_row(editor.cursor_position.row)
+        crossterm::event::KeyCode::Char(' ') => {
+            editor.delete(Selection::Cells { start: editor.cursor_position, end: editor.cursor_position });
         }
-        crossterm::event::KeyCode::Char('c') => {
-            editor.document.delete_column(editor.cursor_position.col)
+        crossterm::event::KeyCode::Char('r' | 'd') => editor.delete(Selection::Rows { start: row, end: row }),
+        crossterm::event::KeyCode::Char('c') => editor.delete(Selection::Columns { start: col, end: col }),
+        crossterm::event::KeyCode::Esc => editor.mode = Mode::Normal,
+        _ => editor.status_message = StatusMessage::from("Unrecognized command"),
+    }
+
+    // a register named before a cancelled delete or yank is dropped with it
+    editor.register_prefix = RegisterPrefix::None;
+    editor.mode = Mode::Normal;
+}
+
+pub fn handle_yank_mode_press(editor: &mut Editor, key: crossterm::event::KeyCode) {
+    let (col, row) = (editor.cursor_position.col, editor.cursor_position.row);
+
+    match key {
+        crossterm::event::KeyCode::Char(' ') => {
+            editor.yank(Selection::Cells { start: editor.cursor_position, end: editor.cursor_position });
         }
+        crossterm::event::KeyCode::Char('r' | 'y') => editor.yank(Selection::Rows { start: row, end: row }),
+        crossterm::event::KeyCode::Char('c') => editor.yank(Selection::Columns { start: col, end: col }),
         crossterm::event::KeyCode::Esc => editor.mode = Mode::Normal,
         _ => editor.status_message = StatusMessage::from("Unrecognized command"),
     }
 
+    editor.register_prefix = RegisterPrefix::None;
     editor.mode = Mode::Normal;
 }
 
@@ -119,11 +203,7 @@ pub fn handle_save_as_mode_press(editor: &mut Editor, key: crossterm::event::Key
     };
 
     match key {
-        crossterm::event::KeyCode::Char(c) => {
-            if !c.is_control() {
-                filename.push(c);
-            }
-        }
+        crossterm::event::KeyCode::Char(c) if !c.is_control() => filename.push(c),
         crossterm::event::KeyCode::Backspace => {
             filename.truncate(filename.len().saturating_sub(1));
         }
@@ -144,7 +224,7 @@ pub fn handle_save_as_mode_press(editor: &mut Editor, key: crossterm::event::Key
                 Err(err) => {
                     editor.status_message = StatusMessage::from(err.to_string());
                 }
-            };
+            }
 
             return;
         }
@@ -168,11 +248,11 @@ pub fn handle_search_mode_press(editor: &mut Editor, key: crossterm::event::KeyC
         crossterm::event::KeyCode::Enter => {
             editor.search();
         }
-        crossterm::event::KeyCode::Delete | crossterm::event::KeyCode::Backspace => {
-            if editor.search_mode == SearchMode::None || editor.search_mode == SearchMode::Error {
-                editor.search_text.handle_delete(key);
-                editor.search_mode = SearchMode::None;
-            }
+        crossterm::event::KeyCode::Delete | crossterm::event::KeyCode::Backspace
+            if editor.search_mode == SearchMode::None || editor.search_mode == SearchMode::Error =>
+        {
+            editor.search_text.handle_delete(key);
+            editor.search_mode = SearchMode::None;
         }
         crossterm::event::KeyCode::Down
         | crossterm::event::KeyCode::Up
@@ -0,0 +1,341 @@
+use std::cmp::Ordering;
+
+use crate::document::Document;
+use crate::editor::Position;
+use crate::functions;
+use crate::parser::{self, Node};
+use crate::value::{CellError, ErrorKind, Value};
+
+// How a lookup treats values that aren't an exact match
+#[derive(Clone, Copy)]
+enum MatchMode {
+    Exact,
+    // the largest value that's less than the one looked up
+    NextSmaller,
+    // the smallest value that's greater than the one looked up
+    NextLarger,
+}
+
+// A rectangular block of cells passed to a lookup function. Cells are read on demand, so huge
+// ranges over sparse sheets don't have to be copied out first.
+struct Table<'a> {
+    start: Position,
+    height: usize,
+    width: usize,
+    document: &'a Document,
+}
+
+impl<'a> Table<'a> {
+    fn from(arg: &Node, document: &'a Document) -> Result<Self, CellError> {
+        let (start, end) = parser::reference_bounds(arg, document)?
+            .ok_or_else(|| CellError::value("Expected a range to look in"))?;
+
+        Ok(Self {
+            start,
+            height: end.row - start.row + 1,
+            width: end.col - start.col + 1,
+            document,
+        })
+    }
+
+    fn get(&self, row: usize, col: usize) -> Value {
+        self.document
+            .get_cell(self.start.col + col, self.start.row + row)
+            .map_or(Value::Empty, |cell| cell.value().clone())
+    }
+
+    // The cells of a single row or column, for functions that search along one line
+    fn line(&self, name: &str) -> Result<Line<'_>, CellError> {
+        if self.width == 1 {
+            Ok(Line::Column(self, self.height))
+        } else if self.height == 1 {
+            Ok(Line::Row(self, self.width))
+        } else {
+            Err(CellError::value(format!(
+                "{name} needs a single row or column"
+            )))
+        }
+    }
+}
+
+enum Line<'a> {
+    Column(&'a Table<'a>, usize),
+    Row(&'a Table<'a>, usize),
+}
+
+impl Line<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Self::Column(_, len) | Self::Row(_, len) => *len,
+        }
+    }
+
+    fn get(&self, idx: usize) -> Value {
+        match self {
+            Self::Column(table, _) => table.get(idx, 0),
+            Self::Row(table, _) => table.get(0, idx),
+        }
+    }
+}
+
+pub fn call(name: &str, args: &[Node], document: &Document) -> Result<Value, CellError> {
+    match name {
+        "VLOOKUP" | "HLOOKUP" => lookup(name, args, document),
+        "INDEX" => index(name, args, document),
+        "MATCH" => find_position(name, args, document),
+        "XLOOKUP" => xlookup(name, args, document),
+        _ => Err(functions::unknown_function(name)),
+    }
+}
+
+// Searches the first column (VLOOKUP) or row (HLOOKUP) of a table, returning the value from the
+// column or row given by the 1 based offset
+fn lookup(name: &str, args: &[Node], document: &Document) -> Result<Value, CellError> {
+    functions::check_arity(name, args, 3, 4)?;
+
+    let lookup = parser::evaluate(&args[0], document)?;
+    let table = Table::from(&args[1], document)?;
+    let offset = position(&args[2], document)?;
+    let mode = match args.get(3) {
+        Some(arg) if !parser::evaluate(arg, document)?.as_bool()? => MatchMode::Exact,
+        _ => MatchMode::NextSmaller,
+    };
+
+    let vertical = name == "VLOOKUP";
+    let (len, across) = if vertical {
+        (table.height, table.width)
+    } else {
+        (table.width, table.height)
+    };
+
+    if offset > across {
+        return Err(CellError::new(
+            ErrorKind::Ref,
+            format!("{name} index {offset} is outside the range"),
+        ));
+    }
+
+    let get = |idx| {
+        if vertical {
+            table.get(idx, 0)
+        } else {
+            table.get(0, idx)
+        }
+    };
+    let found = find(&lookup, len, get, mode).ok_or_else(|| not_found(&lookup))?;
+
+    Ok(if vertical {
+        table.get(found, offset - 1)
+    } else {
+        table.get(offset - 1, found)
+    })
+}
+
+fn index(name: &str, args: &[Node], document: &Document) -> Result<Value, CellError> {
+    functions::check_arity(name, args, 2, 3)?;
+
+    let table = Table::from(&args[0], document)?;
+    let first = position(&args[1], document)?;
+    let second = args.get(2).map(|arg| position(arg, document)).transpose()?;
+
+    // a single number indexes along whichever way a one dimensional range goes
+    let (row, col) = match second {
+        Some(col) => (first, col),
+        None if table.height == 1 => (1, first),
+        None => (first, 1),
+    };
+
+    if row > table.height || col > table.width {
+        return Err(CellError::new(
+            ErrorKind::Ref,
+            "INDEX position is outside the range",
+        ));
+    }
+
+    Ok(table.get(row - 1, col - 1))
+}
+
+fn find_position(name: &str, args: &[Node], document: &Document) -> Result<Value, CellError> {
+    functions::check_arity(name, args, 2, 3)?;
+
+    let lookup = parser::evaluate(&args[0], document)?;
+    let table = Table::from(&args[1], document)?;
+    let line = table.line(name)?;
+    let match_type = match args.get(2) {
+        Some(arg) => parser::evaluate(arg, document)?.as_number()?,
+        None => 1.0,
+    };
+    let mode = match match_type {
+        num if num > 0.0 => MatchMode::NextSmaller,
+        num if num < 0.0 => MatchMode::NextLarger,
+        _ => MatchMode::Exact,
+    };
+
+    let found =
+        find(&lookup, line.len(), |idx| line.get(idx), mode).ok_or_else(|| not_found(&lookup))?;
+
+    #[allow(clippy::cast_precision_loss)]
+    Ok(Value::Number((found + 1) as f64))
+}
+
+fn xlookup(name: &str, args: &[Node], document: &Document) -> Result<Value, CellError> {
+    functions::check_arity(name, args, 3, 5)?;
+
+    let lookup = parser::evaluate(&args[0], document)?;
+    let lookup_table = Table::from(&args[1], document)?;
+    let lookup_line = lookup_table.line(name)?;
+    let return_table = Table::from(&args[2], document)?;
+    let return_line = return_table.line(name)?;
+
+    if lookup_line.len() != return_line.len() {
+        return Err(CellError::value(
+            "XLOOKUP needs lookup and return ranges of the same size",
+        ));
+    }
+
+    let match_mode = match args.get(4) {
+        Some(arg) => parser::evaluate(arg, document)?.as_number()?,
+        None => 0.0,
+    };
+    let mode = match match_mode {
+        0.0 => MatchMode::Exact,
+        -1.0 => MatchMode::NextSmaller,
+        1.0 => MatchMode::NextLarger,
+        num => {
+            return Err(CellError::value(format!(
+                "Unsupported XLOOKUP match mode: {num}"
+            )))
+        }
+    };
+
+    match find(&lookup, lookup_line.len(), |idx| lookup_line.get(idx), mode) {
+        Some(found) => Ok(return_line.get(found)),
+        // the fallback is only evaluated when it's needed
+        None => match args.get(3) {
+            Some(fallback) => parser::evaluate(fallback, document),
+            None => Err(not_found(&lookup)),
+        },
+    }
+}
+
+// Finds the position of `lookup` among the first `len` values returned by `get`. The first exact
+// match always wins. Otherwise, for the approximate modes, the closest value on the requested
+// side is used. Values of a different type than `lookup` (and empty cells) are never matched.
+fn find(
+    lookup: &Value,
+    len: usize,
+    get: impl Fn(usize) -> Value,
+    mode: MatchMode,
+) -> Option<usize> {
+    let mut best: Option<(usize, Value)> = None;
+
+    for idx in 0..len {
+        let candidate = get(idx);
+
+        let Some(ordering) = compare_same_type(&candidate, lookup) else {
+            continue;
+        };
+
+        let better = |best: &Value| {
+            compare_same_type(&candidate, best).is_some_and(|ordering| match mode {
+                MatchMode::NextSmaller => ordering.is_gt(),
+                _ => ordering.is_lt(),
+            })
+        };
+
+        match (mode, ordering) {
+            (_, Ordering::Equal) => return Some(idx),
+            (MatchMode::NextSmaller, Ordering::Less)
+            | (MatchMode::NextLarger, Ordering::Greater)
+                if best.as_ref().is_none_or(|(_, best)| better(best)) =>
+            {
+                best = Some((idx, candidate));
+            }
+            _ => {}
+        }
+    }
+
+    best.map(|(idx, _)| idx)
+}
+
+fn compare_same_type(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(_), Value::Number(_))
+        | (Value::String(_), Value::String(_))
+        | (Value::Boolean(_), Value::Boolean(_)) => a.compare(b).ok(),
+        _ => None,
+    }
+}
+
+// Reads a 1 based row or column number
+fn position(arg: &Node, document: &Document) -> Result<usize, CellError> {
+    let num = parser::evaluate(arg, document)?.as_number()?.trunc();
+
+    if num < 1.0 {
+        return Err(CellError::value(format!(
+            "Expected a position of at least 1, found {num}"
+        )));
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    Ok(num as usize)
+}
+
+fn not_found(lookup: &Value) -> CellError {
+    CellError::new(ErrorKind::NotAvailable, format!("{lookup} was not found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A: names, B: counts, C: sorted prices
+    fn eval(formula: &str) -> String {
+        Document::from_rows(&[
+            &["apple", "1", "10"],
+            &["banana", "2", "20"],
+            &["cherry", "3", "30"],
+        ])
+        .eval(formula)
+    }
+
+    #[test]
+    fn vlookup_and_hlookup() {
+        assert_eq!(eval("VLOOKUP(\"banana\", A1:C3, 2, FALSE)"), "2");
+        assert_eq!(eval("VLOOKUP(25, C1:C3, 1)"), "20");
+        assert_eq!(eval("VLOOKUP(5, C1:C3, 1)"), "#N/A");
+        assert_eq!(eval("VLOOKUP(\"fig\", A1:C3, 2, FALSE)"), "#N/A");
+        assert_eq!(eval("VLOOKUP(\"apple\", A1:C3, 4, FALSE)"), "#REF!");
+        assert_eq!(eval("HLOOKUP(\"apple\", A1:C1, 3, FALSE)"), "#REF!");
+        assert_eq!(eval("HLOOKUP(1, B1:C3, 3, FALSE)"), "3");
+    }
+
+    #[test]
+    fn lookups_only_match_values_of_the_same_type() {
+        assert_eq!(eval("VLOOKUP(\"2\", B1:B3, 1, FALSE)"), "#N/A");
+        assert_eq!(eval("MATCH(2, A1:A3, 0)"), "#N/A");
+    }
+
+    #[test]
+    fn index_and_match() {
+        assert_eq!(eval("INDEX(A1:C3, 3, 2)"), "3");
+        assert_eq!(eval("INDEX(A1:A3, 2)"), "banana");
+        assert_eq!(eval("INDEX(A1:C1, 3)"), "10");
+        assert_eq!(eval("INDEX(A1:C3, 4, 1)"), "#REF!");
+        assert_eq!(eval("MATCH(\"cherry\", A1:A3, 0)"), "3");
+        assert_eq!(eval("MATCH(25, C1:C3)"), "2");
+        assert_eq!(eval("MATCH(25, C1:C3, -1)"), "3");
+        assert_eq!(eval("MATCH(1, A1:C3, 0)"), "#VALUE!");
+    }
+
+    #[test]
+    fn xlookup() {
+        assert_eq!(eval("XLOOKUP(\"cherry\", A1:A3, C1:C3)"), "30");
+        assert_eq!(eval("XLOOKUP(\"fig\", A1:A3, C1:C3)"), "#N/A");
+        assert_eq!(eval("XLOOKUP(\"fig\", A1:A3, C1:C3, \"none\")"), "none");
+        assert_eq!(eval("XLOOKUP(\"apple\", A1:A3, C1:C3, 1/0)"), "10");
+        assert_eq!(eval("XLOOKUP(25, C1:C3, A1:A3, \"\", -1)"), "banana");
+        assert_eq!(eval("XLOOKUP(25, C1:C3, A1:A3, \"\", 1)"), "cherry");
+        assert_eq!(eval("XLOOKUP(1, B1:B3, A1:A2)"), "#VALUE!");
+    }
+}
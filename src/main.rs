@@ -2,12 +2,17 @@
 
 mod cell;
 mod constants;
+mod criteria;
 mod document;
 mod editor;
+mod functions;
 mod handlers;
+mod lookup;
 mod parser;
+mod register;
 mod row;
 mod ui;
+mod value;
 
 use cell::Cell;
 use editor::Editor;
@@ -1,52 +1,1153 @@
-use nom::{IResult, ParseTo};
-use nom::character::{is_digit, is_alphanumeric};
-use nom::bytes::complete::take_while1;
-use nom::Err;
+use std::fmt;
+
+use nom::branch::alt;
+use nom::bytes::complete::{tag, tag_no_case, take_while};
+use nom::character::complete::{
+    alpha1, char, digit0, digit1, multispace0, none_of, one_of, satisfy,
+};
+use nom::combinator::{all_consuming, cut, map, map_opt, not, opt, recognize};
+use nom::error::{context, VerboseError, VerboseErrorKind};
+use nom::multi::many0;
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
+use nom::{IResult, Offset};
+
+type ParseResult<'a, O> = IResult<&'a str, O, VerboseError<&'a str>>;
 
 use crate::document::Document;
+use crate::editor::Position;
+use crate::functions;
+use crate::value::{CellError, ErrorKind, Value};
+
+/// A reference to a single cell, e.g. `B3`. Both indices are zero based. A `$` in front of the
+/// column or row (`$B$3`, `$B3`, `B$3`) marks that part as absolute, so it stays put when the
+/// formula is copied somewhere else.
+#[derive(Clone, Copy)]
+pub struct CellRef {
+    pub row: usize,
+    pub col: usize,
+    pub row_absolute: bool,
+    pub col_absolute: bool,
+}
+
+impl CellRef {
+    fn from_a1(
+        col_absolute: bool,
+        letters: &str,
+        row_absolute: bool,
+        digits: &str,
+    ) -> Option<Self> {
+        let col = column_index(letters)?;
+        let row: usize = digits.parse().ok()?;
+
+        Some(Self {
+            row: row.checked_sub(1)?,
+            col,
+            row_absolute,
+            col_absolute,
+        })
+    }
+}
+
+impl fmt::Display for CellRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let anchor = |absolute: bool| if absolute { "$" } else { "" };
+
+        write!(
+            f,
+            "{}{}{}{}",
+            anchor(self.col_absolute),
+            column_name(self.col),
+            anchor(self.row_absolute),
+            self.row + 1
+        )
+    }
+}
+
+impl CellRef {
+    // Where the reference points once its formula is copied `rows` down and `cols` across
+    fn offset(self, rows: isize, cols: isize) -> Option<Self> {
+        Some(Self {
+            row: if self.row_absolute {
+                self.row
+            } else {
+                self.row.checked_add_signed(rows)?
+            },
+            col: if self.col_absolute {
+                self.col
+            } else {
+                self.col.checked_add_signed(cols)?
+            },
+            ..self
+        })
+    }
+}
+
+impl From<&CellRef> for Position {
+    fn from(cell_ref: &CellRef) -> Self {
+        Self {
+            col: cell_ref.col,
+            row: cell_ref.row,
+        }
+    }
+}
+
+/// A rectangular block of cells, e.g. `A1:C10`. `start` is always the top left corner and `end`
+/// the bottom right, regardless of how the range was written.
+#[derive(Clone, Copy)]
+pub struct Range {
+    pub start: CellRef,
+    pub end: CellRef,
+}
+
+impl Range {
+    fn new(a: CellRef, b: CellRef) -> Self {
+        // each anchor follows the index it was written on
+        let (top, bottom) = if a.row <= b.row { (a, b) } else { (b, a) };
+        let (left, right) = if a.col <= b.col { (a, b) } else { (b, a) };
+
+        Self {
+            start: CellRef {
+                row: top.row,
+                col: left.col,
+                row_absolute: top.row_absolute,
+                col_absolute: left.col_absolute,
+            },
+            end: CellRef {
+                row: bottom.row,
+                col: right.col,
+                row_absolute: bottom.row_absolute,
+                col_absolute: right.col_absolute,
+            },
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.start, self.end)
+    }
+}
+
+/// Either kind of reference that can appear in a formula.
+#[derive(Clone, Copy)]
+pub enum Reference {
+    Cell(CellRef),
+    Range(Range),
+}
+
+impl Reference {
+    /// Where the reference points once its formula is copied `rows` down and `cols` across, e.g.
+    /// `A1` copied one row down is `A2`. Absolute rows and columns stay put. `None` if it would
+    /// end up off the sheet.
+    pub fn offset(self, rows: isize, cols: isize) -> Option<Self> {
+        match self {
+            Self::Cell(cell_ref) => cell_ref.offset(rows, cols).map(Self::Cell),
+            Self::Range(range) => Some(Self::Range(Range::new(
+                range.start.offset(rows, cols)?,
+                range.end.offset(rows, cols)?,
+            ))),
+        }
+    }
+}
+
+/// A run of rows or columns being inserted or deleted, which moves the cells after it.
+#[derive(Clone, Copy)]
+pub enum StructuralEdit {
+    InsertRows { at: usize, count: usize },
+    DeleteRows { at: usize, count: usize },
+    InsertColumns { at: usize, count: usize },
+    DeleteColumns { at: usize, count: usize },
+}
+
+impl StructuralEdit {
+    /// Where a reference ends up after the edit, or `None` if everything it pointed at was
+    /// deleted. Like other spreadsheets, absolute references move too: `$` only matters when a
+    /// formula is copied, not when the cells it points at move.
+    pub fn apply(self, reference: Reference) -> Option<Reference> {
+        match reference {
+            Reference::Cell(cell_ref) => self.apply_cell(cell_ref).map(Reference::Cell),
+            Reference::Range(range) => {
+                let (start, end) = match self {
+                    // a range loses the deleted lines, and only disappears if nothing is left
+                    Self::DeleteRows { at, .. } => {
+                        let start = self.apply_row(range.start.row).unwrap_or(at);
+                        let end = self.apply_row(range.end.row).or(at.checked_sub(1))?;
+
+                        if start > end {
+                            return None;
+                        }
+
+                        (
+                            CellRef {
+                                row: start,
+                                ..range.start
+                            },
+                            CellRef {
+                                row: end,
+                                ..range.end
+                            },
+                        )
+                    }
+                    Self::DeleteColumns { at, .. } => {
+                        let start = self.apply_column(range.start.col).unwrap_or(at);
+                        let end = self.apply_column(range.end.col).or(at.checked_sub(1))?;
+
+                        if start > end {
+                            return None;
+                        }
+
+                        (
+                            CellRef {
+                                col: start,
+                                ..range.start
+                            },
+                            CellRef {
+                                col: end,
+                                ..range.end
+                            },
+                        )
+                    }
+                    _ => (self.apply_cell(range.start)?, self.apply_cell(range.end)?),
+                };
+
+                Some(Reference::Range(Range::new(start, end)))
+            }
+        }
+    }
+
+    /// Where the cell at `pos` ends up after the edit, or `None` if it was deleted.
+    pub fn apply_position(self, pos: Position) -> Option<Position> {
+        Some(Position {
+            row: self.apply_row(pos.row)?,
+            col: self.apply_column(pos.col)?,
+        })
+    }
+
+    /// Where row `row` ends up after the edit, or `None` if it was deleted.
+    pub fn apply_row(self, row: usize) -> Option<usize> {
+        match self {
+            Self::InsertRows { at, count } if row >= at => row.checked_add(count),
+            Self::DeleteRows { at, count } => delete_lines(row, at, count),
+            _ => Some(row),
+        }
+    }
+
+    /// Where column `col` ends up after the edit, or `None` if it was deleted.
+    pub fn apply_column(self, col: usize) -> Option<usize> {
+        match self {
+            Self::InsertColumns { at, count } if col >= at => col.checked_add(count),
+            Self::DeleteColumns { at, count } => delete_lines(col, at, count),
+            _ => Some(col),
+        }
+    }
+
+    fn apply_cell(self, cell_ref: CellRef) -> Option<CellRef> {
+        Some(CellRef {
+            row: self.apply_row(cell_ref.row)?,
+            col: self.apply_column(cell_ref.col)?,
+            ..cell_ref
+        })
+    }
+}
+
+fn delete_lines(line: usize, at: usize, count: usize) -> Option<usize> {
+    if line < at {
+        Some(line)
+    } else if line - at < count {
+        None
+    } else {
+        Some(line - count)
+    }
+}
+
+/// The 0 based index of a column given by its letters, the inverse of `column_name`.
+pub fn column_index(letters: &str) -> Option<usize> {
+    if letters.is_empty() || !letters.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let mut col: usize = 0;
+
+    for c in letters.chars() {
+        let digit = usize::from(c.to_ascii_uppercase() as u8 - b'A') + 1;
+        col = col.checked_mul(26)?.checked_add(digit)?;
+    }
 
-struct CellRef {
-    row: String,
-    col: u32,
+    Some(col - 1)
 }
 
-enum ValueItem {
+/// Converts a zero based column index into its letters, e.g. `0 -> A`, `26 -> AA`.
+pub fn column_name(col: usize) -> String {
+    let mut letters = vec![];
+    let mut remaining = col + 1;
+
+    while remaining > 0 {
+        let digit = (remaining - 1) % 26;
+        letters.push(char::from(b'A' + u8::try_from(digit).unwrap_or_default()));
+        remaining = (remaining - 1) / 26;
+    }
+
+    letters.iter().rev().collect()
+}
+
+#[derive(Clone, Copy)]
+pub enum BinaryOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    Concat,
+}
+
+#[derive(Clone, Copy)]
+pub enum UnaryOperator {
+    Negate,
+    Plus,
+}
+
+pub enum ValueItem {
     CellRef(CellRef),
-    Number(u32),
-    Error(String)
+    Range(Range),
+    Number(f64),
+    String(String),
+    Boolean(bool),
+    // a name defined with `:name`, uppercased
+    Name(String),
+    // children are the arguments
+    Function(String),
+    BinaryOp(BinaryOperator),
+    UnaryOp(UnaryOperator),
+    Error(CellError),
+}
+
+pub struct Node {
+    pub children: Vec<Node>,
+    pub value: ValueItem,
+}
+
+impl Node {
+    fn leaf(value: ValueItem) -> Self {
+        Self {
+            children: vec![],
+            value,
+        }
+    }
+
+    fn binary(op: BinaryOperator, lhs: Node, rhs: Node) -> Self {
+        Self {
+            children: vec![lhs, rhs],
+            value: ValueItem::BinaryOp(op),
+        }
+    }
+
+    fn unary(op: UnaryOperator, operand: Node) -> Self {
+        Self {
+            children: vec![operand],
+            value: ValueItem::UnaryOp(op),
+        }
+    }
+}
+
+/// Where and why a formula failed to parse.
+pub struct ParseError {
+    // byte offset into the formula, not counting its leading `=`
+    pub offset: usize,
+    // the same place in characters, 1 based and counting the leading `=` to match what's shown in
+    // the cell
+    pub column: usize,
+    pub found: Option<char>,
+    pub expected: Option<String>,
+}
+
+impl ParseError {
+    fn new(input: &str, err: &VerboseError<&str>) -> Self {
+        // the first entry is the innermost parser, which is the one that actually gave up
+        let Some((remaining, _)) = err.errors.first() else {
+            return Self {
+                offset: 0,
+                column: 2,
+                found: input.chars().next(),
+                expected: None,
+            };
+        };
+
+        // outer parsers at the same spot may know better what was expected than e.g. `digit1`
+        let expected = err
+            .errors
+            .iter()
+            .filter(|(at, _)| at.len() == remaining.len())
+            .find_map(|(_, kind)| match kind {
+                VerboseErrorKind::Char(c) => Some(format!("'{c}'")),
+                VerboseErrorKind::Context(context) => Some((*context).to_string()),
+                VerboseErrorKind::Nom(_) => None,
+            });
+
+        let offset = input.offset(remaining);
+
+        Self {
+            offset,
+            column: input[..offset].chars().count() + 2,
+            found: remaining.chars().next(),
+            expected,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.found {
+            Some(c) => write!(f, "unexpected '{c}' at column {}", self.column)?,
+            None => write!(f, "unexpected end of formula")?,
+        }
+
+        if let Some(expected) = &self.expected {
+            write!(f, ", expected {expected}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Evaluates a parsed formula into the value shown in its cell.
+pub fn evaluate_formula(formula: &Result<Node, ParseError>, document: &Document) -> Value {
+    match formula {
+        Ok(node) => match evaluate(node, document) {
+            // a formula pointing at an empty cell shows 0, not nothing
+            Ok(Value::Empty) => Value::Number(0.0),
+            Ok(value) => value,
+            Err(err) => Value::Error(err),
+        },
+        Err(err) => Value::Error(CellError {
+            offset: Some(err.offset),
+            ..CellError::new(ErrorKind::Parse, err.to_string())
+        }),
+    }
+}
+
+/// Returns the top left and bottom right corners of every cell and range referenced by a formula.
+/// Ranges aren't expanded, so a huge range costs no more than a single cell.
+pub fn references(formula: &Node, document: &Document) -> Vec<(Position, Position)> {
+    let mut refs = vec![];
+
+    collect_references(formula, document, &mut refs);
+
+    refs
+}
+
+/// Passes every reference in `formula` (without its `=`) through `rewrite`, leaving the rest of
+/// the text as the user typed it. References that `rewrite` drops are replaced with `#REF!`.
+pub fn rewrite_references(
+    formula: &str,
+    mut rewrite: impl FnMut(Reference) -> Option<Reference>,
+) -> String {
+    let mut rewritten = String::with_capacity(formula.len());
+    let mut rest = formula;
+
+    while let Some(c) = rest.chars().next() {
+        // a reference can't start halfway through a name or a number
+        let at_boundary = !formula[..formula.offset(rest)].ends_with(is_name_char);
+
+        // strings, function names and numbers are copied as is, so `"A1"`, `LOG10(` and `1E5`
+        // aren't mistaken for references
+        let verbatim = |input| {
+            map(
+                alt((
+                    recognize(string),
+                    recognize(terminated(identifier, pair(multispace0, char('(')))),
+                )),
+                |_| None,
+            )(input)
+        };
+        let other = map(alt((identifier, recognize(number))), |_| None);
+
+        if let Ok((remaining, reference)) = alt((verbatim, reference_token, other))(rest) {
+            let consumed = &rest[..rest.offset(remaining)];
+
+            match reference {
+                Some(reference) if at_boundary => match rewrite(reference) {
+                    Some(Reference::Cell(cell_ref)) => rewritten.push_str(&cell_ref.to_string()),
+                    Some(Reference::Range(range)) => rewritten.push_str(&range.to_string()),
+                    None => rewritten.push_str(ErrorKind::Ref.code()),
+                },
+                _ => rewritten.push_str(consumed),
+            }
+
+            rest = remaining;
+        } else {
+            rewritten.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    rewritten
+}
+
+fn reference_token(input: &str) -> ParseResult<'_, Option<Reference>> {
+    terminated(
+        alt((
+            map(
+                separated_pair(a1, delimited(multispace0, char(':'), multispace0), a1),
+                |(start, end)| Some(Reference::Range(Range::new(start, end))),
+            ),
+            map(a1, |cell_ref| Some(Reference::Cell(cell_ref))),
+        )),
+        not(satisfy(is_name_char)),
+    )(input)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$')
+}
+
+/// Parses a formula (without its leading `=`).
+pub fn parse_formula(input: &str) -> Result<Node, ParseError> {
+    match all_consuming(parse_node)(input) {
+        Ok((_, node)) => Ok(node),
+        Err(nom::Err::Error(err) | nom::Err::Failure(err)) => Err(ParseError::new(input, &err)),
+        // only streaming parsers ask for more input
+        Err(nom::Err::Incomplete(_)) => Err(ParseError {
+            offset: input.len(),
+            column: input.chars().count() + 2,
+            found: None,
+            expected: None,
+        }),
+    }
+}
+
+fn collect_references(node: &Node, document: &Document, refs: &mut Vec<(Position, Position)>) {
+    match &node.value {
+        ValueItem::CellRef(cell_ref) => {
+            refs.push((Position::from(cell_ref), Position::from(cell_ref)));
+        }
+        ValueItem::Range(range) => {
+            refs.push((Position::from(&range.start), Position::from(&range.end)));
+        }
+        ValueItem::Name(name) => {
+            if let Ok(definition) = resolve_name(name, document) {
+                collect_references(definition, document, refs);
+            }
+        }
+        _ => {}
+    }
+
+    for child in &node.children {
+        collect_references(child, document, refs);
+    }
+}
+
+/// Checks that `name` can be used in formulas: it has to look like a function name, without
+/// being mistaken for a cell reference or a boolean.
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if all_consuming(identifier)(name).is_err() {
+        return Err(format!(
+            "Invalid name: {name} - must start with a letter and only contain letters, digits, \
+             `_` and `.`"
+        ));
+    }
+
+    if all_consuming(a1)(name).is_ok()
+        || name.eq_ignore_ascii_case("TRUE")
+        || name.eq_ignore_ascii_case("FALSE")
+    {
+        return Err(format!(
+            "Invalid name: {name} - already means something else"
+        ));
+    }
+
+    Ok(())
+}
+
+/// Parses what a name stands for: usually a reference, but any formula (e.g. a constant like
+/// `9.81`) works, as long as it doesn't use other names.
+pub fn parse_name_definition(definition: &str) -> Result<Node, String> {
+    let node = parse_formula(definition).map_err(|err| err.to_string())?;
+
+    if uses_names(&node) {
+        return Err("A name can't be defined in terms of other names".to_string());
+    }
+
+    Ok(node)
+}
+
+fn uses_names(node: &Node) -> bool {
+    matches!(node.value, ValueItem::Name(_)) || node.children.iter().any(uses_names)
+}
+
+/// Looks up the definition of a name used in a formula.
+pub fn resolve_name<'a>(name: &str, document: &'a Document) -> Result<&'a Node, CellError> {
+    document
+        .name_node(name)
+        .ok_or_else(|| CellError::new(ErrorKind::Name, format!("Unknown name: {name}")))
+}
+
+/// The top left and bottom right corners of a function argument that's a reference (a cell,
+/// range, or a name for either), or `None` for any other kind of argument.
+pub fn reference_bounds(
+    node: &Node,
+    document: &Document,
+) -> Result<Option<(Position, Position)>, CellError> {
+    match &node.value {
+        ValueItem::CellRef(cell_ref) => Ok(Some((cell_ref.into(), cell_ref.into()))),
+        ValueItem::Range(range) => Ok(Some(((&range.start).into(), (&range.end).into()))),
+        ValueItem::Name(name) => reference_bounds(resolve_name(name, document)?, document),
+        _ => Ok(None),
+    }
+}
+
+// Grammar, from lowest to highest precedence:
+//
+//   comparison := concat (('=' | '<>' | '<=' | '>=' | '<' | '>') concat)*
+//   concat     := expression ('&' expression)*
+//   expression := term (('+' | '-') term)*
+//   term       := power (('*' | '/') power)*
+//   power      := prefix ('^' power)?
+//   prefix     := ('-' | '+') prefix | primary
+//   primary    := function | boolean | range | cell_ref | name | number | string | error
+//                 | '(' comparison ')'
+//   function   := identifier '(' (comparison (',' comparison)*)? ')'
+//   boolean    := 'TRUE' | 'FALSE'
+//   range      := cell_ref ':' cell_ref
+//   cell_ref   := '$'? letters '$'? digits
+//   name       := identifier
+//   number     := (digits ('.' digits?)? | '.' digits) (('e' | 'E') ('+' | '-')? digits)?
+//   string     := '"' (any character except '"' | '""')* '"'
+//   error      := '#DIV/0!' | '#REF!' | '#NAME?' | '#VALUE!' | '#NUM!' | '#PARSE!' | '#CIRC!'
+//                 | '#N/A'
+//
+// `+ - * /` are left associative and `^` is right associative, so `2^3^2` is `2^9`. A leading
+// sign applies before `^`, so `-2^2` is `4` as in other spreadsheets.
+fn parse_node(input: &str) -> ParseResult<'_, Node> {
+    delimited(multispace0, comparison, multispace0)(input)
+}
+
+fn comparison(input: &str) -> ParseResult<'_, Node> {
+    let (input, first) = concat(input)?;
+    let (input, rest) = many0(pair(
+        token(alt((
+            map(tag("<>"), |_| BinaryOperator::NotEqual),
+            map(tag("<="), |_| BinaryOperator::LessEqual),
+            map(tag(">="), |_| BinaryOperator::GreaterEqual),
+            map(char('='), |_| BinaryOperator::Equal),
+            map(char('<'), |_| BinaryOperator::Less),
+            map(char('>'), |_| BinaryOperator::Greater),
+        ))),
+        cut(concat),
+    ))(input)?;
+
+    Ok((input, fold_left(first, rest)))
+}
+
+fn concat(input: &str) -> ParseResult<'_, Node> {
+    let (input, first) = expression(input)?;
+    let (input, rest) = many0(pair(
+        token(map(char('&'), |_| BinaryOperator::Concat)),
+        cut(expression),
+    ))(input)?;
+
+    Ok((input, fold_left(first, rest)))
 }
 
-struct Node {
-    children: Vec<Node>,
-    value: ValueItem,
+fn expression(input: &str) -> ParseResult<'_, Node> {
+    let (input, first) = term(input)?;
+    let (input, rest) = many0(pair(
+        token(alt((
+            map(char('+'), |_| BinaryOperator::Add),
+            map(char('-'), |_| BinaryOperator::Subtract),
+        ))),
+        cut(term),
+    ))(input)?;
+
+    Ok((input, fold_left(first, rest)))
+}
+
+fn term(input: &str) -> ParseResult<'_, Node> {
+    let (input, first) = power(input)?;
+    let (input, rest) = many0(pair(
+        token(alt((
+            map(char('*'), |_| BinaryOperator::Multiply),
+            map(char('/'), |_| BinaryOperator::Divide),
+        ))),
+        cut(power),
+    ))(input)?;
+
+    Ok((input, fold_left(first, rest)))
 }
 
-pub fn parse(input: Option<&str>, document: &Document) -> String {
-    if input.is_none() {
-        return "".to_string();
+fn power(input: &str) -> ParseResult<'_, Node> {
+    let (input, base) = prefix(input)?;
+
+    match preceded(token(char('^')), cut(power))(input) {
+        Ok((input, exponent)) => Ok((input, Node::binary(BinaryOperator::Power, base, exponent))),
+        Err(nom::Err::Error(_)) => Ok((input, base)),
+        Err(err) => Err(err),
     }
+}
+
+fn prefix(input: &str) -> ParseResult<'_, Node> {
+    alt((
+        map(
+            pair(
+                token(alt((
+                    map(char('-'), |_| UnaryOperator::Negate),
+                    map(char('+'), |_| UnaryOperator::Plus),
+                ))),
+                prefix,
+            ),
+            |(op, operand)| Node::unary(op, operand),
+        ),
+        primary,
+    ))(input)
+}
+
+fn primary(input: &str) -> ParseResult<'_, Node> {
+    let result = alt((
+        function,
+        boolean,
+        range,
+        cell_ref,
+        name,
+        number,
+        string,
+        error,
+        preceded(
+            token(char('(')),
+            cut(terminated(comparison, token(char(')')))),
+        ),
+    ))(input);
+
+    // every alternative failed at the same spot, so don't blame whichever happened to go last
+    result.map_err(|err| match err {
+        nom::Err::Error(_) => nom::Err::Error(VerboseError {
+            errors: vec![(input, VerboseErrorKind::Context("a value"))],
+        }),
+        err => err,
+    })
+}
+
+// Error literals, e.g. the `#REF!` left behind when a referenced row is deleted
+fn error(input: &str) -> ParseResult<'_, Node> {
+    map(
+        token(map_opt(
+            alt((
+                tag_no_case("#N/A"),
+                recognize(tuple((
+                    char('#'),
+                    take_while(|c: char| c.is_ascii_alphanumeric() || c == '/'),
+                    one_of("!?"),
+                ))),
+            )),
+            ErrorKind::from_code,
+        )),
+        |kind| Node::leaf(ValueItem::Error(CellError::from(kind))),
+    )(input)
+}
+
+fn function(input: &str) -> ParseResult<'_, Node> {
+    map(
+        pair(
+            terminated(token(identifier), char('(')),
+            cut(terminated(arguments, token(char(')')))),
+        ),
+        |(name, args)| Node {
+            children: args,
+            value: ValueItem::Function(name.to_ascii_uppercase()),
+        },
+    )(input)
+}
+
+fn arguments(input: &str) -> ParseResult<'_, Vec<Node>> {
+    let (input, first) = opt(parse_node)(input)?;
+
+    let Some(first) = first else {
+        return Ok((input, vec![]));
+    };
+
+    let (input, rest) = many0(preceded(char(','), cut(parse_node)))(input)?;
+
+    Ok((input, std::iter::once(first).chain(rest).collect()))
+}
+
+fn identifier(input: &str) -> ParseResult<'_, &str> {
+    recognize(pair(
+        satisfy(|c| c.is_ascii_alphabetic()),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+    ))(input)
+}
+
+fn boolean(input: &str) -> ParseResult<'_, Node> {
+    map(
+        token(terminated(
+            alt((
+                map(tag_no_case("TRUE"), |_| true),
+                map(tag_no_case("FALSE"), |_| false),
+            )),
+            not(satisfy(|c| {
+                c.is_ascii_alphanumeric() || c == '_' || c == '.'
+            })),
+        )),
+        |b| Node::leaf(ValueItem::Boolean(b)),
+    )(input)
+}
+
+fn range(input: &str) -> ParseResult<'_, Node> {
+    map(
+        token(separated_pair(
+            a1,
+            token(char(':')),
+            cut(context("a cell reference", a1)),
+        )),
+        |(start, end)| Node::leaf(ValueItem::Range(Range::new(start, end))),
+    )(input)
+}
+
+fn cell_ref(input: &str) -> ParseResult<'_, Node> {
+    map(
+        token(terminated(a1, not(satisfy(is_name_char)))),
+        |cell_ref| Node::leaf(ValueItem::CellRef(cell_ref)),
+    )(input)
+}
+
+fn name(input: &str) -> ParseResult<'_, Node> {
+    map(token(identifier), |name| {
+        Node::leaf(ValueItem::Name(name.to_ascii_uppercase()))
+    })(input)
+}
+
+fn a1(input: &str) -> ParseResult<'_, CellRef> {
+    map_opt(
+        tuple((anchor, alpha1, anchor, digit1)),
+        |(col_absolute, letters, row_absolute, digits)| {
+            CellRef::from_a1(col_absolute, letters, row_absolute, digits)
+        },
+    )(input)
+}
+
+fn anchor(input: &str) -> ParseResult<'_, bool> {
+    map(opt(char('$')), |anchor| anchor.is_some())(input)
+}
+
+// Literals that don't fit in an f64 (e.g. `1e999`) still parse, but evaluate to an error instead
+// of silently becoming infinity
+fn number(input: &str) -> ParseResult<'_, Node> {
+    map(
+        token(recognize(tuple((
+            alt((
+                recognize(pair(digit1, opt(pair(char('.'), digit0)))),
+                recognize(pair(char('.'), digit1)),
+            )),
+            opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+        )))),
+        |literal: &str| match literal.parse::<f64>() {
+            Ok(num) if num.is_finite() => Node::leaf(ValueItem::Number(num)),
+            _ => Node::leaf(ValueItem::Error(CellError::new(
+                ErrorKind::Num,
+                format!("Number out of range: {literal}"),
+            ))),
+        },
+    )(input)
+}
+
+fn string(input: &str) -> ParseResult<'_, Node> {
+    map(
+        token(preceded(
+            char('"'),
+            cut(terminated(
+                many0(alt((none_of("\""), map(tag("\"\""), |_| '"')))),
+                char('"'),
+            )),
+        )),
+        |chars| Node::leaf(ValueItem::String(chars.into_iter().collect())),
+    )(input)
+}
+
+fn token<'a, O>(
+    parser: impl FnMut(&'a str) -> ParseResult<'a, O>,
+) -> impl FnMut(&'a str) -> ParseResult<'a, O> {
+    delimited(multispace0, parser, multispace0)
+}
+
+fn fold_left(first: Node, rest: Vec<(BinaryOperator, Node)>) -> Node {
+    rest.into_iter()
+        .fold(first, |lhs, (op, rhs)| Node::binary(op, lhs, rhs))
+}
+
+pub fn evaluate(node: &Node, document: &Document) -> Result<Value, CellError> {
+    match &node.value {
+        ValueItem::Number(num) => Ok(Value::Number(*num)),
+        ValueItem::String(text) => Ok(Value::String(text.clone())),
+        ValueItem::Boolean(b) => Ok(Value::Boolean(*b)),
+        ValueItem::CellRef(cell_ref) => resolve(cell_ref, document),
+        ValueItem::Range(_) => Err(CellError::value("A range can't be used as a single value")),
+        ValueItem::Name(name) => evaluate(resolve_name(name, document)?, document),
+        ValueItem::Function(name) => functions::call(name, &node.children, document),
+        ValueItem::Error(err) => Err(err.clone()),
+        ValueItem::UnaryOp(op) => {
+            let operand = evaluate(&node.children[0], document)?.as_number()?;
+
+            match op {
+                UnaryOperator::Negate => Ok(Value::Number(-operand)),
+                UnaryOperator::Plus => Ok(Value::Number(operand)),
+            }
+        }
+        ValueItem::BinaryOp(op) => {
+            let lhs = evaluate(&node.children[0], document)?;
+            let rhs = evaluate(&node.children[1], document)?;
 
-    match parse_node(input.unwrap().as_bytes()).value {
-        ValueItem::CellRef(val) => return "TODO: CellRef Not Yet Implemented".to_string(),
-        ValueItem::Number(val) => return val.to_string(),
-        ValueItem::Error(val) => return val
+            apply(*op, &lhs, &rhs)
+        }
     }
 }
 
-// TODO: change function signature to return an IResult
-fn parse_node(input: &[u8]) -> Node {
-    let number: IResult<&[u8], &[u8]> = take_while1(is_digit)(input);
+fn apply(op: BinaryOperator, lhs: &Value, rhs: &Value) -> Result<Value, CellError> {
+    let ordering = || lhs.compare(rhs);
+
+    let result = match op {
+        BinaryOperator::Equal => return Ok(Value::Boolean(ordering()?.is_eq())),
+        BinaryOperator::NotEqual => return Ok(Value::Boolean(ordering()?.is_ne())),
+        BinaryOperator::Less => return Ok(Value::Boolean(ordering()?.is_lt())),
+        BinaryOperator::LessEqual => return Ok(Value::Boolean(ordering()?.is_le())),
+        BinaryOperator::Greater => return Ok(Value::Boolean(ordering()?.is_gt())),
+        BinaryOperator::GreaterEqual => return Ok(Value::Boolean(ordering()?.is_ge())),
+        BinaryOperator::Concat => {
+            return Ok(Value::String(lhs.as_text()? + &rhs.as_text()?));
+        }
+        BinaryOperator::Add => lhs.as_number()? + rhs.as_number()?,
+        BinaryOperator::Subtract => lhs.as_number()? - rhs.as_number()?,
+        BinaryOperator::Multiply => lhs.as_number()? * rhs.as_number()?,
+        BinaryOperator::Divide => {
+            let (lhs, rhs) = (lhs.as_number()?, rhs.as_number()?);
+
+            if rhs == 0.0 {
+                return Err(CellError::from(ErrorKind::DivZero));
+            }
+
+            lhs / rhs
+        }
+        BinaryOperator::Power => lhs.as_number()?.powf(rhs.as_number()?),
+    };
+
+    number_value(result)
+}
+
+/// Wraps the result of a calculation, turning infinities and NaN into an error.
+pub fn number_value(num: f64) -> Result<Value, CellError> {
+    if num.is_finite() {
+        Ok(Value::Number(num))
+    } else {
+        Err(CellError::from(ErrorKind::Num))
+    }
+}
 
-    match number {
-        Ok((remainder, val)) => {
-            let num: Option<u32> = val.parse_to();
-            return Node{children: vec!(), value: ValueItem::Number(num.unwrap())}
+fn resolve(cell_ref: &CellRef, document: &Document) -> Result<Value, CellError> {
+    match document.get_cell(cell_ref.col, cell_ref.row) {
+        Some(cell) => match cell.value() {
+            Value::Error(err) => Err(err.clone()),
+            value => Ok(value.clone()),
         },
-        Err(_) => {
-            return Node{ children: vec!(), value: ValueItem::Error("TODO: Not Yet Implemented".to_string()) };
-            // TODO: parse cell ref
+        None => Ok(Value::Empty),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::eval;
+
+    fn parse_error(formula: &str) -> ParseError {
+        match parse_formula(formula) {
+            Ok(_) => panic!("{formula} should not parse"),
+            Err(err) => err,
         }
     }
 
-//    let (remainder, (number, cell_ref)) = (take_while1(is_digit), take_while1(is_alphanumeric)).parse(input)?;
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("2+3*4"), "14");
+        assert_eq!(eval("2*3+4"), "10");
+        assert_eq!(eval("(2+3)*4"), "20");
+        assert_eq!(eval("2*3^2"), "18");
+    }
+
+    #[test]
+    fn associativity() {
+        assert_eq!(eval("1-2-3"), "-4");
+        assert_eq!(eval("8/4/2"), "1");
+        assert_eq!(eval("2^3^2"), "512");
+    }
+
+    #[test]
+    fn unary_minus() {
+        // a leading sign binds tighter than `^`, like other spreadsheets
+        assert_eq!(eval("-2^2"), "4");
+        assert_eq!(eval("0-2^2"), "-4");
+        assert_eq!(eval("-2^3^2"), "-512");
+        assert_eq!(eval("(-2)^2"), "4");
+        assert_eq!(eval("2^-1"), "0.5");
+        assert_eq!(eval("-(1+2)"), "-3");
+        assert_eq!(eval("--3"), "3");
+    }
+
+    #[test]
+    fn parse_error_offsets() {
+        let err = parse_error("1+)");
+        assert_eq!((err.offset, err.found), (2, Some(')')));
+        assert_eq!(err.expected.as_deref(), Some("a value"));
+
+        let err = parse_error("(1");
+        assert_eq!((err.offset, err.found), (2, None));
+        assert_eq!(err.expected.as_deref(), Some("')'"));
+
+        assert_eq!(parse_error("1 2").offset, 2);
+        assert_eq!(parse_error("*").offset, 0);
+        assert_eq!(parse_error("").offset, 0);
+    }
+
+    #[test]
+    fn parse_error_columns_count_characters() {
+        assert_eq!(
+            parse_error("1+)").to_string(),
+            "unexpected ')' at column 4, expected a value"
+        );
+
+        let err = parse_error("\"é\"+)");
+        assert_eq!((err.offset, err.column), (5, 6));
+        assert!(err.to_string().starts_with("unexpected ')' at column 6"));
+    }
+
+    fn shift(formula: &str, edit: StructuralEdit) -> String {
+        rewrite_references(formula, |reference| edit.apply(reference))
+    }
+
+    #[test]
+    fn inserting_moves_references_at_or_after_the_new_line() {
+        assert_eq!(
+            shift("A1+A2+A3", StructuralEdit::InsertRows { at: 1, count: 1 }),
+            "A1+A3+A4"
+        );
+        assert_eq!(
+            shift(
+                "SUM(A1:C3)",
+                StructuralEdit::InsertColumns { at: 1, count: 1 }
+            ),
+            "SUM(A1:D3)"
+        );
+        assert_eq!(
+            shift("$B$2*2", StructuralEdit::InsertRows { at: 0, count: 1 }),
+            "$B$3*2"
+        );
+        assert_eq!(
+            shift(
+                "A1+SUM(B2:B3)",
+                StructuralEdit::InsertRows { at: 1, count: 3 }
+            ),
+            "A1+SUM(B5:B6)"
+        );
+    }
+
+    #[test]
+    fn deleting_breaks_references_to_the_deleted_line() {
+        assert_eq!(
+            shift("A1+A2+A3", StructuralEdit::DeleteRows { at: 1, count: 1 }),
+            "A1+#REF!+A2"
+        );
+        assert_eq!(
+            shift("B1*C1", StructuralEdit::DeleteColumns { at: 1, count: 1 }),
+            "#REF!*B1"
+        );
+        assert_eq!(eval("#REF!+1"), "#REF!");
+    }
+
+    #[test]
+    fn deleting_inside_a_range_shrinks_it() {
+        assert_eq!(
+            shift("SUM(A1:A5)", StructuralEdit::DeleteRows { at: 2, count: 1 }),
+            "SUM(A1:A4)"
+        );
+        assert_eq!(
+            shift("SUM(A1:A5)", StructuralEdit::DeleteRows { at: 0, count: 1 }),
+            "SUM(A1:A4)"
+        );
+        assert_eq!(
+            shift(
+                "SUM(A2:C2)",
+                StructuralEdit::DeleteColumns { at: 2, count: 1 }
+            ),
+            "SUM(A2:B2)"
+        );
+        assert_eq!(
+            shift("SUM(A3:A3)", StructuralEdit::DeleteRows { at: 2, count: 1 }),
+            "SUM(#REF!)"
+        );
+    }
+
+    #[test]
+    fn deleting_several_lines_at_once() {
+        let edit = StructuralEdit::DeleteRows { at: 1, count: 3 };
+
+        assert_eq!(shift("A1+A3+A5", edit), "A1+#REF!+A2");
+        assert_eq!(shift("SUM(A1:A10)", edit), "SUM(A1:A7)");
+        assert_eq!(shift("SUM(A3:A8)", edit), "SUM(A2:A5)");
+        assert_eq!(shift("SUM(A2:A4)", edit), "SUM(#REF!)");
+        assert_eq!(
+            shift(
+                "SUM(A1:D1)",
+                StructuralEdit::DeleteColumns { at: 0, count: 2 }
+            ),
+            "SUM(A1:B1)"
+        );
+    }
+
+    #[test]
+    fn rewriting_leaves_strings_functions_and_numbers_alone() {
+        let edit = StructuralEdit::InsertRows { at: 0, count: 1 };
+
+        assert_eq!(shift("LOG10(A1)", edit), "LOG10(A2)");
+        assert_eq!(shift("\"A1\"&A1", edit), "\"A1\"&A2");
+        assert_eq!(shift("1E5+B2", edit), "1E5+B3");
+        assert_eq!(shift("sum( a1 )", edit), "sum( A2 )");
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(eval(".5"), "0.5");
+        assert_eq!(eval("1."), "1");
+        assert_eq!(eval("1e-3"), "0.001");
+        assert_eq!(eval("2.5E+2"), "250");
+        assert_eq!(eval("1e999"), "#NUM!");
+        assert_eq!(eval("1e"), "#PARSE!");
+        assert_eq!(eval("1.2.3"), "#PARSE!");
+    }
+
+    #[test]
+    fn comparisons_across_types() {
+        // numbers sort before text, and text before booleans, like other spreadsheets
+        assert_eq!(eval("1=\"1\""), "FALSE");
+        assert_eq!(eval("1<>\"1\""), "TRUE");
+        assert_eq!(eval("1<\"a\""), "TRUE");
+        assert_eq!(eval("TRUE>1"), "TRUE");
+        assert_eq!(eval("TRUE=1"), "FALSE");
+
+        // text compares case insensitively, and an empty cell equals both 0 and ""
+        assert_eq!(eval("\"a\"=\"A\""), "TRUE");
+        assert_eq!(eval("\"a\"<\"B\""), "TRUE");
+        assert_eq!(eval("A1=0"), "TRUE");
+        assert_eq!(eval("A1=\"\""), "TRUE");
+    }
+
+    #[test]
+    fn logical_functions() {
+        assert_eq!(eval("IF(1>2, \"yes\", \"no\")"), "no");
+        assert_eq!(eval("IF(FALSE, 1)"), "FALSE");
+        assert_eq!(eval("IF(TRUE, 1, 1/0)"), "1");
+        assert_eq!(eval("AND(TRUE, 1, 2>1)"), "TRUE");
+        assert_eq!(eval("OR(FALSE, 0)"), "FALSE");
+        assert_eq!(eval("NOT(0)"), "TRUE");
+    }
 }
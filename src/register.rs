@@ -0,0 +1,305 @@
+use crate::document::Document;
+use crate::editor::{Position, Selection};
+use crate::parser::{self, CellRef, Range, Reference, StructuralEdit};
+use crate::Cell;
+
+/// The register used when none is named with `"`.
+pub const UNNAMED: char = '"';
+
+/// Whether a register holds a block of cells or whole rows or columns, which decides whether
+/// pasting it overwrites cells or inserts new rows or columns.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Cells,
+    Rows,
+    Columns,
+}
+
+/// Cells copied by a yank or a delete, kept as they were typed so formulas can be pasted again.
+#[derive(Clone)]
+pub struct Register {
+    pub shape: Shape,
+    // the top left corner of what was copied, which formulas are adjusted relative to
+    origin: Position,
+    // the number of rows and columns copied. Only the one along the shape applies to whole rows
+    // or columns.
+    pub height: usize,
+    pub width: usize,
+    // the text of each non-empty cell, by its offset from `origin`
+    cells: Vec<(Position, String)>,
+    /// Formulas elsewhere that referenced nothing but the deleted rows or columns, by where they
+    /// were left with their text from before the delete. Empty for yanks.
+    pub broken: Vec<(Position, String)>,
+}
+
+impl Register {
+    pub fn from(document: &Document, selection: Selection) -> Self {
+        let (shape, start, end) = match selection {
+            Selection::Cells { start, end } => (Shape::Cells, start, end),
+            Selection::Rows { start, end } => (
+                Shape::Rows,
+                Position { col: 0, row: start },
+                Position {
+                    col: usize::MAX,
+                    row: end,
+                },
+            ),
+            Selection::Columns { start, end } => (
+                Shape::Columns,
+                Position { col: start, row: 0 },
+                Position {
+                    col: end,
+                    row: usize::MAX,
+                },
+            ),
+        };
+
+        let cells = document
+            .cells_in_range(&start, &end)
+            .into_iter()
+            .map(|(pos, cell)| {
+                (
+                    Position {
+                        col: pos.col - start.col,
+                        row: pos.row - start.row,
+                    },
+                    cell.text().to_string(),
+                )
+            })
+            .collect();
+
+        Self {
+            shape,
+            origin: start,
+            height: end.row.saturating_sub(start.row).saturating_add(1),
+            width: end.col.saturating_sub(start.col).saturating_add(1),
+            cells,
+            broken: vec![],
+        }
+    }
+
+    /// The cells to write to paste the register with its top left corner at `target`. Relative
+    /// references in formulas move by as much as the cells do, like copying in other
+    /// spreadsheets.
+    pub fn cells_at(&self, target: Position) -> Vec<(Position, String)> {
+        let rows = distance(self.origin.row, target.row);
+        let cols = distance(self.origin.col, target.col);
+
+        self.cells
+            .iter()
+            .map(|(offset, text)| {
+                let pos = Position {
+                    col: target.col.saturating_add(offset.col),
+                    row: target.row.saturating_add(offset.row),
+                };
+
+                let text = match text.strip_prefix('=') {
+                    Some(formula) => format!(
+                        "={}",
+                        parser::rewrite_references(formula, |reference| reference
+                            .offset(rows, cols))
+                    ),
+                    None => text.clone(),
+                };
+
+                (pos, text)
+            })
+            .collect()
+    }
+
+    /// The formulas to rewrite once the deleted rows or columns are pasted back in at `target`,
+    /// so references that broke when they were deleted point at them again and the delete and
+    /// paste move them. Also returns how many broken formulas were edited since and are left
+    /// alone.
+    pub fn restored_references(
+        &self,
+        document: &Document,
+        target: Position,
+    ) -> (Vec<(Position, String)>, usize) {
+        let (deleted, inserted) = match self.shape {
+            Shape::Cells => return (vec![], 0),
+            Shape::Rows => (
+                StructuralEdit::DeleteRows {
+                    at: self.origin.row,
+                    count: self.height,
+                },
+                StructuralEdit::InsertRows {
+                    at: target.row,
+                    count: self.height,
+                },
+            ),
+            Shape::Columns => (
+                StructuralEdit::DeleteColumns {
+                    at: self.origin.col,
+                    count: self.width,
+                },
+                StructuralEdit::InsertColumns {
+                    at: target.col,
+                    count: self.width,
+                },
+            ),
+        };
+
+        let was_deleted = |cell_ref: &CellRef| match self.shape {
+            Shape::Cells => false,
+            Shape::Rows => cell_ref.row.wrapping_sub(self.origin.row) < self.height,
+            Shape::Columns => cell_ref.col.wrapping_sub(self.origin.col) < self.width,
+        };
+        let moved = |cell_ref: CellRef| match self.shape {
+            Shape::Cells => cell_ref,
+            Shape::Rows => CellRef {
+                row: cell_ref.row - self.origin.row + target.row,
+                ..cell_ref
+            },
+            Shape::Columns => CellRef {
+                col: cell_ref.col - self.origin.col + target.col,
+                ..cell_ref
+            },
+        };
+
+        let mut restored = vec![];
+        let mut edited = 0;
+
+        for (pos, text) in &self.broken {
+            let Some(formula) = text.strip_prefix('=') else {
+                continue;
+            };
+
+            // the paste moved the formula along with everything else after the target, so it has
+            // to look the same as the delete and the paste together would have left it
+            let Some(pos) = inserted.apply_position(*pos) else {
+                continue;
+            };
+            let shifted = parser::rewrite_references(formula, |reference| {
+                inserted.apply(deleted.apply(reference)?)
+            });
+
+            if document.get_cell(pos.col, pos.row).map(Cell::text) != Some(&format!("={shifted}")) {
+                edited += 1;
+                continue;
+            }
+
+            let formula = parser::rewrite_references(formula, |reference| match reference {
+                Reference::Cell(cell_ref) if was_deleted(&cell_ref) => {
+                    Some(Reference::Cell(moved(cell_ref)))
+                }
+                Reference::Range(range) if was_deleted(&range.start) && was_deleted(&range.end) => {
+                    Some(Reference::Range(Range {
+                        start: moved(range.start),
+                        end: moved(range.end),
+                    }))
+                }
+                _ => inserted.apply(deleted.apply(reference)?),
+            });
+
+            restored.push((pos, format!("={formula}")));
+        }
+
+        (restored, edited)
+    }
+}
+
+fn distance(from: usize, to: usize) -> isize {
+    if to >= from {
+        isize::try_from(to - from).unwrap_or(isize::MAX)
+    } else {
+        -isize::try_from(from - to).unwrap_or(isize::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::position;
+
+    // what `paste` does with whole rows
+    fn paste_rows(document: &mut Document, register: &Register, row: usize) -> usize {
+        let target = Position { col: 0, row };
+        let mut cells = register.cells_at(target);
+
+        document.insert_rows(row, register.height);
+
+        let (restored, edited) = register.restored_references(document, target);
+        cells.extend(restored);
+        document.set_cells(cells);
+
+        edited
+    }
+
+    fn pasted(register: &Register, target: &str) -> Vec<(Position, String)> {
+        register.cells_at(position(target))
+    }
+
+    #[test]
+    fn pasting_moves_relative_references_with_the_cells() {
+        let document = Document::from_rows(&[&["1", "=A1*2"], &["", "=$A1+A$1+$A$1"]]);
+        let register = Register::from(
+            &document,
+            Selection::Cells {
+                start: position("B1"),
+                end: position("B2"),
+            },
+        );
+
+        let cells = pasted(&register, "D3");
+        let at = |idx: usize| (cells[idx].0.col, cells[idx].0.row);
+        assert_eq!(at(0), (3, 2));
+        assert_eq!(cells[0].1, "=C3*2");
+        assert_eq!(at(1), (3, 3));
+        assert_eq!(cells[1].1, "=$A3+C$1+$A$1");
+
+        assert_eq!(pasted(&register, "B1")[1].1, "=$A1+A$1+$A$1");
+    }
+
+    #[test]
+    fn references_pasted_off_the_sheet_break() {
+        let document = Document::from_rows(&[&["1"], &["=A1+1", "=SUM(A1:A2)"]]);
+        let register = Register::from(&document, Selection::Rows { start: 1, end: 1 });
+
+        let cells = pasted(&register, "A1");
+        assert_eq!(cells[0].1, "=#REF!+1");
+        assert_eq!(cells[1].1, "=SUM(#REF!)");
+
+        assert_eq!(pasted(&register, "A4")[0].1, "=A3+1");
+    }
+
+    #[test]
+    fn text_is_pasted_as_is() {
+        let document = Document::from_rows(&[&["A1", "B1+1"]]);
+        let register = Register::from(&document, Selection::Rows { start: 0, end: 0 });
+
+        let cells = pasted(&register, "A5");
+        assert_eq!(cells[0].1, "A1");
+        assert_eq!(cells[1].1, "B1+1");
+    }
+
+    #[test]
+    fn deleting_and_pasting_rows_moves_them() {
+        let mut document = Document::from_rows(&[&["1"], &["2"], &["=A1+A2"], &["=SUM(A2:A2)"]]);
+
+        let mut register = Register::from(&document, Selection::Rows { start: 1, end: 1 });
+        register.broken = document.delete_rows(1, 1);
+        assert_eq!(document.get_cell(0, 1).unwrap().text(), "=A1+#REF!");
+        assert_eq!(register.broken.len(), 2);
+
+        assert_eq!(paste_rows(&mut document, &register, 3), 0);
+        assert_eq!(document.get_cell(0, 1).unwrap().text(), "=A1+A4");
+        assert_eq!(document.get_cell(0, 2).unwrap().text(), "=SUM(A4:A4)");
+        assert_eq!(document.shown("A2"), "3");
+        assert_eq!(document.shown("A3"), "2");
+    }
+
+    #[test]
+    fn formulas_edited_after_the_delete_stay_broken() {
+        let mut document = Document::from_rows(&[&["1"], &["2"], &["=A1+A2"], &["=A2*2"]]);
+
+        let mut register = Register::from(&document, Selection::Rows { start: 1, end: 1 });
+        register.broken = document.delete_rows(1, 1);
+        document.set(&[("A2", "=A1+#REF!+1")]);
+
+        assert_eq!(paste_rows(&mut document, &register, 0), 1);
+        assert_eq!(document.get_cell(0, 2).unwrap().text(), "=A2+#REF!+1");
+        assert_eq!(document.get_cell(0, 3).unwrap().text(), "=A1*2");
+        assert_eq!(document.shown("A4"), "4");
+    }
+}
@@ -10,7 +10,7 @@ pub struct Row {
 
 impl Row {
     pub fn at(&self, x: usize) -> Option<&Cell> {
-        return self.cells.get(&x);
+        self.cells.get(&x)
     }
 
     pub fn insert_at(&mut self, col_idx: usize, c: char) {
@@ -26,13 +26,46 @@ impl Row {
     }
 
     pub fn get_mut(&mut self, x: usize) -> Option<&mut Cell> {
-        return self.cells.get_mut(&x);
+        self.cells.get_mut(&x)
+    }
+
+    pub fn columns(&self) -> impl Iterator<Item = usize> + '_ {
+        self.cells.keys().copied()
+    }
+
+    /// Appends the cells between `start` and `end` (inclusive) to `cells`, ordered by column.
+    pub fn cells_in<'a>(&'a self, start: usize, end: usize, cells: &mut Vec<(usize, &'a Cell)>) {
+        if end - start < self.cells.len() {
+            cells.extend(
+                (start..=end)
+                    .filter_map(|col_idx| self.cells.get(&col_idx).map(|cell| (col_idx, cell))),
+            );
+        } else {
+            let first = cells.len();
+
+            cells.extend(
+                self.cells
+                    .iter()
+                    .filter(|(col_idx, _)| (start..=end).contains(*col_idx))
+                    .map(|(col_idx, cell)| (*col_idx, cell)),
+            );
+            cells[first..].sort_unstable_by_key(|(col_idx, _)| *col_idx);
+        }
     }
 
     pub fn max_col(&self) -> usize {
         self.max_col
     }
 
+    /// Replaces the cell at `col_idx` with a new one holding `text`.
+    pub fn set_cell(&mut self, col_idx: usize, text: String) {
+        self.cells.insert(col_idx, Cell::from(text));
+
+        if col_idx > self.max_col {
+            self.max_col = col_idx;
+        }
+    }
+
     pub fn init_cell_at(&mut self, col_idx: usize, str: String) {
         self.cells.insert(col_idx, Cell::from(str));
         self.max_col = col_idx;
@@ -42,24 +75,27 @@ impl Row {
         self.cells.remove(&col_idx);
     }
 
-    pub fn insert_column(&mut self, at: usize) {
+    /// Inserts `count` empty columns at `at`, moving the cells from there on right.
+    pub fn insert_columns(&mut self, at: usize, count: usize) {
         for idx in (at..self.max_col.saturating_add(1)).rev() {
             if let Some(cell) = self.cells.remove(&idx) {
-                self.cells.insert(idx + 1, cell);
+                self.cells.insert(idx + count, cell);
             }
         }
 
-        self.max_col = self.max_col.saturating_add(1);
+        self.max_col = self.max_col.saturating_add(count);
     }
 
-    pub fn delete_column(&mut self, at: usize) {
-        self.cells.remove(&at);
+    /// Removes `count` columns from `at` onwards, moving the cells after them left.
+    pub fn delete_columns(&mut self, at: usize, count: usize) {
         let mut new_max_col = self.max_col;
 
-        for idx in at.saturating_add(1)..self.max_col.saturating_add(1) {
+        for idx in at..self.max_col.saturating_add(1) {
             if let Some(cell) = self.cells.remove(&idx) {
-                self.cells.insert(idx - 1, cell);
-                new_max_col = idx - 1;
+                if idx - at >= count {
+                    self.cells.insert(idx - count, cell);
+                    new_max_col = idx - count;
+                }
             }
         }
 
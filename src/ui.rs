@@ -1,16 +1,22 @@
 use std::rc::Rc;
 
-use crate::constants;
 use crate::editor::Mode;
+use crate::editor::Position;
 use crate::editor::SearchMode;
+use crate::parser;
+use crate::value::Value;
+use crate::Cell;
 use crate::Editor;
 
+use ratatui::layout::Alignment;
 use ratatui::layout::Constraint;
 use ratatui::layout::Direction;
 use ratatui::layout::Layout;
 use ratatui::layout::Rect;
 use ratatui::style::Modifier;
 use ratatui::style::Style;
+use ratatui::text::Line;
+use ratatui::text::Span;
 use ratatui::widgets::Block;
 use ratatui::widgets::BorderType;
 use ratatui::widgets::Borders;
@@ -30,65 +36,139 @@ pub struct Rect {
 }
 */
 
-fn create_layouts(frame: &Frame) -> Vec<Rc<[Rect]>> {
-    let layout = Layout::default()
+// Background of the cells selected in the visual modes
+const SELECTION_COLOR: ratatui::style::Color = ratatui::style::Color::Rgb(60, 60, 100);
+
+// Where the headers and each visible cell go within the area given to the spreadsheet
+struct SheetLayout {
+    row_header: Rect,
+    column_header: Rect,
+    grid: Rect,
+    // indexed by column, then row
+    cells: Vec<Vec<Rect>>,
+}
+
+// As many whole cells as fit, at their column's width and their row's height. There's always at
+// least one, clipped to the area if the terminal is too small for it.
+fn create_layouts(editor: &Editor, area: Rect) -> SheetLayout {
+    // the offset from the top of the grid and the height of each row
+    let mut rows: Vec<(u16, u16)> = vec![];
+    let mut y: u16 = 0;
+
+    loop {
+        let height = row_span(editor, editor.viewbox_anchor.row + rows.len());
+
+        if !rows.is_empty() && y.saturating_add(height) > area.height.saturating_sub(1) {
+            break;
+        }
+
+        rows.push((y, height));
+        y = y.saturating_add(height);
+    }
+
+    // wide enough for any row number that can come into view before the cursor does
+    let last_row = editor.viewbox_anchor.row.max(editor.cursor_position.row) + rows.len();
+    let row_header_width = u16::try_from(last_row.to_string().len() + 1).unwrap_or(u16::MAX);
+
+    let columns = Layout::default()
         .direction(Direction::Horizontal)
-        .constraints([
-            Constraint::Ratio(1, 10),
-            Constraint::Ratio(1, 10),
-            Constraint::Ratio(1, 10),
-            Constraint::Ratio(1, 10),
-            Constraint::Ratio(1, 10),
-            Constraint::Ratio(1, 10),
-            Constraint::Ratio(1, 10),
-            Constraint::Ratio(1, 10),
-            Constraint::Ratio(1, 10),
-            Constraint::Ratio(1, 10),
-        ])
-        .split(frame.size());
-
-    let mut sub_layouts: Vec<Rc<[Rect]>> = vec![];
-
-    for i in 0..layout.len() {
-        sub_layouts.push(
-            Layout::default()
-                .direction(Direction::Vertical)
-                .constraints([
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::Length(4),
-                    Constraint::default(),
-                ])
-                .split(layout[i]),
+        .constraints([Constraint::Length(row_header_width), Constraint::Min(0)])
+        .split(area);
+    let sections = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(1), Constraint::Min(0)])
+        .split(columns[1]);
+    let grid = sections[1];
+
+    let mut cells: Vec<Vec<Rect>> = vec![];
+    let mut x = grid.x;
+
+    loop {
+        let width = column_span(editor, editor.viewbox_anchor.col + cells.len());
+
+        if !cells.is_empty() && x.saturating_add(width) > grid.right() {
+            break;
+        }
+
+        cells.push(
+            rows.iter()
+                .map(|(y, height)| {
+                    Rect {
+                        x,
+                        y: grid.y.saturating_add(*y),
+                        width,
+                        height: *height,
+                    }
+                    .intersection(grid)
+                })
+                .collect(),
         );
+        x = x.saturating_add(width);
+    }
+
+    SheetLayout {
+        row_header: columns[0],
+        column_header: sections[0],
+        grid,
+        cells,
     }
+}
+
+// The width and height available to the cells in a frame of the given size
+pub fn viewbox_size(editor: &Editor, frame: Rect) -> (u16, u16) {
+    let grid = create_layouts(editor, split_frame(frame)[1]).grid;
 
-    sub_layouts
+    (grid.width, grid.height)
 }
 
-fn draw_spreadsheet(frame: &mut Frame, editor: &Editor) {
-    let mut size = frame.size();
-    size.width /= constants::SHEET_VIEWBOX_WIDTH;
-    size.height /= constants::SHEET_VIEWBOX_HEIGHT;
+// How many terminal columns a column takes up, borders or separator included
+pub fn column_span(editor: &Editor, col: usize) -> u16 {
+    let decoration = if editor.dense { 1 } else { 2 };
+
+    editor.column_width(col).saturating_add(decoration)
+}
 
+// How many terminal lines a row takes up
+pub fn row_span(editor: &Editor, row: usize) -> u16 {
+    if editor.dense {
+        1
+    } else {
+        editor.row_height(row)
+    }
+}
+
+// The formula bar, the spreadsheet and the status line
+fn split_frame(area: Rect) -> Rc<[Rect]> {
+    Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .split(area)
+}
+
+fn draw_spreadsheet(frame: &mut Frame, editor: &Editor, area: Rect) {
     let mut viewbox_row: u16 = 0;
     let mut viewbox_col: u16 = 0;
     let mut row = editor.viewbox_anchor.row;
     let mut col = editor.viewbox_anchor.col;
 
-    let layouts = create_layouts(frame);
+    let layout = create_layouts(editor, area);
+    let viewbox_width = layout.cells.len();
+    let viewbox_height = layout.cells[0].len();
+    let selection = editor.selection();
+
+    draw_headers(frame, editor, &layout);
 
-    while viewbox_row < constants::SHEET_VIEWBOX_HEIGHT {
-        while viewbox_col < constants::SHEET_VIEWBOX_WIDTH {
+    if editor.dense {
+        draw_dense_cells(frame, editor, &layout);
+        return;
+    }
+
+    while usize::from(viewbox_row) < viewbox_height {
+        while usize::from(viewbox_col) < viewbox_width {
             let text = editor.view(col, row);
 
             let current_cell =
@@ -118,44 +198,216 @@ fn draw_spreadsheet(frame: &mut Frame, editor: &Editor) {
                 .border_style(border_style)
                 .border_type(border_type);
 
-            let widget = Paragraph::new(text).block(block);
+            let selected =
+                selection.is_some_and(|selection| selection.contains(Position { col, row }));
+            let style = if selected {
+                Style::new().bg(SELECTION_COLOR)
+            } else {
+                Style::new()
+            };
+
+            let widget = Paragraph::new(text).style(style).block(block);
 
             frame.render_widget(
                 widget,
-                layouts[usize::from(viewbox_col)][usize::from(viewbox_row)],
+                layout.cells[usize::from(viewbox_col)][usize::from(viewbox_row)],
             );
 
             viewbox_col += 1;
             col += 1;
         }
 
-        col -= usize::from(constants::SHEET_VIEWBOX_WIDTH);
+        col -= viewbox_width;
         row += 1;
         viewbox_col = 0;
         viewbox_row += 1;
     }
 }
 
-fn draw_status_message(frame: &mut Frame, editor: &Editor) {
+// One line per row, with a thin separator after each column. The whole grid is a single widget,
+// so this stays quick with hundreds of cells on screen.
+fn draw_dense_cells(frame: &mut Frame, editor: &Editor, layout: &SheetLayout) {
+    let separator_style = Style::new().fg(ratatui::style::Color::Rgb(100, 100, 100));
+    let selection = editor.selection();
+
+    let lines: Vec<Line> = (0..layout.cells[0].len())
+        .map(|viewbox_row| {
+            let row = editor.viewbox_anchor.row + viewbox_row;
+
+            let spans = (0..layout.cells.len()).flat_map(|viewbox_col| {
+                let col = editor.viewbox_anchor.col + viewbox_col;
+                let text = editor.view(col, row);
+
+                let current_cell =
+                    (row == editor.cursor_position.row) && (col == editor.cursor_position.col);
+                let style = if current_cell {
+                    Style::new().add_modifier(Modifier::BOLD | Modifier::REVERSED)
+                } else if should_highlight_cell(editor, &text, col, row) {
+                    Style::new()
+                        .add_modifier(Modifier::BOLD | Modifier::UNDERLINED)
+                        .fg(ratatui::style::Color::Rgb(220, 220, 220))
+                } else if selection
+                    .is_some_and(|selection| selection.contains(Position { col, row }))
+                {
+                    Style::new().bg(SELECTION_COLOR)
+                } else {
+                    Style::new()
+                };
+
+                let width = usize::from(editor.column_width(col));
+
+                [
+                    Span::styled(format!("{text:width$}"), style),
+                    Span::styled("│", separator_style),
+                ]
+            });
+
+            Line::from(spans.collect::<Vec<Span>>())
+        })
+        .collect();
+
+    frame.render_widget(Paragraph::new(lines), layout.grid);
+}
+
+// Column letters above the grid and row numbers to its left, lined up with the cells' text
+fn draw_headers(frame: &mut Frame, editor: &Editor, layout: &SheetLayout) {
+    let style = |current: bool| {
+        if current {
+            Style::new()
+                .add_modifier(Modifier::BOLD | Modifier::REVERSED)
+                .fg(ratatui::style::Color::Rgb(220, 220, 220))
+        } else {
+            Style::new().fg(ratatui::style::Color::Rgb(100, 100, 100))
+        }
+    };
+
+    for (viewbox_col, cells) in layout.cells.iter().enumerate() {
+        let col = editor.viewbox_anchor.col + viewbox_col;
+        let cell = cells[0];
+
+        frame.render_widget(
+            Paragraph::new(parser::column_name(col))
+                .alignment(Alignment::Center)
+                .style(style(col == editor.cursor_position.col)),
+            Rect {
+                x: cell.x,
+                y: layout.column_header.y,
+                width: cell.width,
+                height: 1,
+            },
+        );
+    }
+
+    for (viewbox_row, cell) in layout.cells[0].iter().enumerate() {
+        let row = editor.viewbox_anchor.row + viewbox_row;
+
+        // the row's text sits just below the top border of its cells, unless they have none
+        let text_offset = u16::from(!editor.dense);
+        if cell.height <= text_offset {
+            break;
+        }
+
+        frame.render_widget(
+            Paragraph::new(format!("{} ", row + 1))
+                .alignment(Alignment::Right)
+                .style(style(row == editor.cursor_position.row)),
+            Rect {
+                x: layout.row_header.x,
+                y: cell.y + text_offset,
+                width: layout.row_header.width,
+                height: 1,
+            },
+        );
+    }
+}
+
+// The current cell's address, its full text and, for formulas, the result
+fn draw_formula_bar(frame: &mut Frame, editor: &Editor, area: Rect) {
+    let position = editor.cursor_position;
+    let cell = editor.document.get_cell(position.col, position.row);
+    let editing = editor.get_mode() == &Mode::Insert;
+
+    let address = format!(" {position} ");
+    let text = cell.map_or("", Cell::text);
+    // the result is stale while the formula is being edited
+    let value = match cell {
+        Some(cell) if cell.formula().is_some() && !editing => format!(" = {} ", cell.value()),
+        _ => String::new(),
+    };
+    let value_width = u16::try_from(value.chars().count())
+        .unwrap_or(u16::MAX)
+        .min(area.width / 3);
+
+    let layout = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Length(u16::try_from(address.len() + 1).unwrap_or(u16::MAX)),
+            Constraint::Min(0),
+            Constraint::Length(value_width),
+        ])
+        .split(area);
+
+    // scroll long text so the cursor stays visible while editing
+    let cursor = text
+        .get(..cell.map_or(0, Cell::cursor_position))
+        .map_or(0, |before| before.chars().count());
+    let width = usize::from(layout[1].width);
+    let scroll = if editing {
+        cursor.saturating_sub(width.saturating_sub(1))
+    } else {
+        0
+    };
+
+    frame.render_widget(
+        Paragraph::new(address)
+            .style(Style::new().add_modifier(Modifier::REVERSED | Modifier::BOLD)),
+        layout[0],
+    );
+    frame.render_widget(
+        Paragraph::new(text.chars().skip(scroll).collect::<String>()),
+        layout[1],
+    );
+    frame.render_widget(
+        Paragraph::new(value).style(Style::new().fg(ratatui::style::Color::Rgb(150, 150, 150))),
+        layout[2],
+    );
+
+    if editing {
+        frame.set_cursor(
+            layout[1].x + u16::try_from(cursor - scroll).unwrap_or_default(),
+            layout[1].y,
+        );
+    }
+}
+
+fn draw_status_message(frame: &mut Frame, editor: &Editor, area: Rect) {
     let message = if editor.get_mode() == &Mode::Command {
         format!(":{}", &editor.command.to_string())
     } else if editor.get_mode() == &Mode::Search && editor.search_mode != SearchMode::Error {
         format!("/{}", &editor.search_text.to_string())
+    } else if editor.status_message.text.is_empty() && editor.selection().is_some() {
+        match editor.get_mode() {
+            Mode::VisualRow => String::from("-- VISUAL ROW --"),
+            Mode::VisualColumn => String::from("-- VISUAL COLUMN --"),
+            _ => String::from("-- VISUAL --"),
+        }
+    } else if editor.status_message.text.is_empty() {
+        // explain the error under the cursor, since the cell itself only has room for its code
+        match editor
+            .document
+            .get_cell(editor.cursor_position.col, editor.cursor_position.row)
+            .map(Cell::value)
+        {
+            Some(Value::Error(err)) => format!("{err} {}", err.message),
+            _ => String::new(),
+        }
     } else {
         editor.status_message.text.clone()
     };
 
     let widget = Paragraph::new(message.clone());
 
-    let size = frame.size();
-    let rect = Rect {
-        x: size.x,
-        y: size.y + size.height - 1,
-        width: size.width,
-        height: 1,
-    };
-
-    frame.render_widget(widget, rect);
+    frame.render_widget(widget, area);
 }
 
 fn should_highlight_cell(editor: &Editor, text: &str, col: usize, row: usize) -> bool {
@@ -179,6 +431,9 @@ fn should_highlight_cell(editor: &Editor, text: &str, col: usize, row: usize) ->
 }
 
 pub fn draw(frame: &mut Frame, editor: &Editor) {
-    draw_spreadsheet(frame, editor);
-    draw_status_message(frame, editor);
+    let layout = split_frame(frame.size());
+
+    draw_formula_bar(frame, editor, layout[0]);
+    draw_spreadsheet(frame, editor, layout[1]);
+    draw_status_message(frame, editor, layout[2]);
 }
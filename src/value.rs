@@ -0,0 +1,236 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// The result of evaluating a formula, or the literal held by a plain cell.
+#[derive(Clone, Default)]
+pub enum Value {
+    #[default]
+    Empty,
+    Number(f64),
+    String(String),
+    Boolean(bool),
+    Error(CellError),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    DivZero,
+    Ref,
+    Name,
+    Value,
+    Num,
+    Parse,
+    Circ,
+    NotAvailable,
+}
+
+impl ErrorKind {
+    const ALL: [Self; 8] = [
+        Self::DivZero,
+        Self::Ref,
+        Self::Name,
+        Self::Value,
+        Self::Num,
+        Self::Parse,
+        Self::Circ,
+        Self::NotAvailable,
+    ];
+
+    /// The compact form shown inside a cell.
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::DivZero => "#DIV/0!",
+            Self::Ref => "#REF!",
+            Self::Name => "#NAME?",
+            Self::Value => "#VALUE!",
+            Self::Num => "#NUM!",
+            Self::Parse => "#PARSE!",
+            Self::Circ => "#CIRC!",
+            Self::NotAvailable => "#N/A",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.code().eq_ignore_ascii_case(code))
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Self::DivZero => "Division by zero",
+            Self::Ref => "Reference to a cell that no longer exists",
+            Self::Name => "Unknown name",
+            Self::Value => "Wrong type of value",
+            Self::Num => "Number out of range",
+            Self::Parse => "Could not parse formula",
+            Self::Circ => "Circular reference",
+            Self::NotAvailable => "No matching value found",
+        }
+    }
+}
+
+/// An error value. Displays as its compact code, while `message` holds the details shown in the
+/// status bar.
+#[derive(Clone)]
+pub struct CellError {
+    pub kind: ErrorKind,
+    pub message: String,
+    // for parse errors, the byte offset into the formula (after its `=`) where parsing failed
+    pub offset: Option<usize>,
+}
+
+impl CellError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            offset: None,
+        }
+    }
+
+    pub fn value(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Value, message)
+    }
+}
+
+impl From<ErrorKind> for CellError {
+    fn from(kind: ErrorKind) -> Self {
+        Self::new(kind, kind.description())
+    }
+}
+
+impl fmt::Display for CellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind.code())
+    }
+}
+
+impl Value {
+    /// Interprets the text of a cell that isn't a formula.
+    pub fn from_literal(text: &str) -> Self {
+        let trimmed = text.trim();
+
+        if trimmed.is_empty() {
+            return Self::Empty;
+        }
+
+        if let Some(num) = trimmed.parse::<f64>().ok().filter(|num| num.is_finite()) {
+            return Self::Number(num);
+        }
+
+        if trimmed.eq_ignore_ascii_case("TRUE") {
+            Self::Boolean(true)
+        } else if trimmed.eq_ignore_ascii_case("FALSE") {
+            Self::Boolean(false)
+        } else {
+            Self::String(text.to_string())
+        }
+    }
+
+    pub fn as_number(&self) -> Result<f64, CellError> {
+        match self {
+            Self::Empty => Ok(0.0),
+            Self::Number(num) => Ok(*num),
+            Self::Boolean(b) => Ok(f64::from(u8::from(*b))),
+            Self::String(text) => text
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|num| num.is_finite())
+                .ok_or_else(|| CellError::value(format!("Expected a number, found \"{text}\""))),
+            Self::Error(err) => Err(err.clone()),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, CellError> {
+        match self {
+            Self::Empty => Ok(false),
+            Self::Number(num) => Ok(*num != 0.0),
+            Self::Boolean(b) => Ok(*b),
+            Self::String(text) => {
+                if text.trim().eq_ignore_ascii_case("TRUE") {
+                    Ok(true)
+                } else if text.trim().eq_ignore_ascii_case("FALSE") {
+                    Ok(false)
+                } else {
+                    Err(CellError::value(format!(
+                        "Expected TRUE or FALSE, found \"{text}\""
+                    )))
+                }
+            }
+            Self::Error(err) => Err(err.clone()),
+        }
+    }
+
+    pub fn as_text(&self) -> Result<String, CellError> {
+        match self {
+            Self::Error(err) => Err(err.clone()),
+            _ => Ok(self.to_string()),
+        }
+    }
+
+    /// Orders two values the way spreadsheets do: numbers sort before text, which sorts before
+    /// booleans, and text compares case insensitively. Empty cells act as the zero value of
+    /// whatever they're compared against.
+    pub fn compare(&self, other: &Self) -> Result<Ordering, CellError> {
+        match (self, other) {
+            (Self::Error(err), _) | (_, Self::Error(err)) => Err(err.clone()),
+            (Self::Empty, Self::Empty) => Ok(Ordering::Equal),
+            (Self::Empty, _) => other.zero().compare(other),
+            (_, Self::Empty) => self.compare(&self.zero()),
+            (Self::Number(a), Self::Number(b)) => Ok(a.total_cmp(b)),
+            (Self::String(a), Self::String(b)) => Ok(a.to_lowercase().cmp(&b.to_lowercase())),
+            (Self::Boolean(a), Self::Boolean(b)) => Ok(a.cmp(b)),
+            _ => Ok(self.type_rank().cmp(&other.type_rank())),
+        }
+    }
+
+    fn zero(&self) -> Self {
+        match self {
+            Self::Number(_) => Self::Number(0.0),
+            Self::String(_) => Self::String(String::new()),
+            Self::Boolean(_) => Self::Boolean(false),
+            Self::Empty | Self::Error(_) => Self::Empty,
+        }
+    }
+
+    fn type_rank(&self) -> u8 {
+        match self {
+            Self::Empty | Self::Number(_) => 0,
+            Self::String(_) => 1,
+            Self::Boolean(_) => 2,
+            Self::Error(_) => 3,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => Ok(()),
+            Self::Number(num) => write!(f, "{}", format_number(*num)),
+            Self::String(text) => write!(f, "{text}"),
+            Self::Boolean(b) => write!(f, "{}", if *b { "TRUE" } else { "FALSE" }),
+            Self::Error(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+/// Formats a number the way it should be displayed in a cell. Results are rounded to 15
+/// significant digits so floating point noise (`0.1 + 0.2`) doesn't leak into the sheet.
+pub fn format_number(num: f64) -> String {
+    let rounded = round_significant(num);
+
+    if rounded == 0.0 {
+        // avoid displaying `-0`
+        return "0".to_string();
+    }
+
+    rounded.to_string()
+}
+
+/// Rounds to the 15 significant digits an f64 can reliably hold, dropping floating point noise.
+pub fn round_significant(num: f64) -> f64 {
+    format!("{num:.14e}").parse().unwrap_or(num)
+}